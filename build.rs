@@ -1,8 +1,11 @@
 use std::env;
-use std::path::PathBuf;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
 
 fn main() {
     println!("cargo:rerun-if-changed=build.rs");
+    git_hash();
 
     let bindings = bindgen::Builder::default()
         .header_contents(
@@ -21,16 +24,23 @@ fn main() {
         .allowlist_type("sasl_secret_t")
         .allowlist_type("sasl_security_properties_t")
         .allowlist_type("sasl_callback_t")
+        // Auxiliary properties, through the utils prop_* functions
+        .allowlist_type("propctx")
+        .allowlist_type("propval")
+        .allowlist_function("prop_request")
+        .allowlist_function("prop_set")
         .allowlist_var("SASL_OK")
         .allowlist_var("SASL_CONTINUE")
         .allowlist_var("SASL_INTERACT")
         .allowlist_var("SASL_FAIL")
         .allowlist_var("SASL_NOMEM")
         .allowlist_var("SASL_BADPROT")
+        .allowlist_var("SASL_BADAUTH")
         .allowlist_var("SASL_BADPARAM")
         .allowlist_var("SASL_TRYAGAIN")
         .allowlist_var("SASL_BADVERS")
         .allowlist_var("SASL_CB_LIST_END")
+        .allowlist_var("SASL_CB_USER")
         .allowlist_var("SASL_CB_AUTHNAME")
         .allowlist_var("SASL_CB_PASS")
         .allowlist_var("SASL_CU_AUTHID")
@@ -56,3 +66,34 @@ fn main() {
         .write_to_file(out_path.join("sasl_bindings.rs"))
         .expect("Couldn't write SASL bindings");
 }
+
+/// Pass the git commit being built to `sasl_xoauth2_version`, or `unknown`
+/// outside a git checkout, e.g. from a release tarball.
+fn git_hash() {
+    let hash = Command::new("git")
+        .args(["rev-parse", "--short=12", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|hash| hash.trim().to_string())
+        .filter(|hash| !hash.is_empty())
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=SASL_XOAUTH2_GIT_HASH={}", hash);
+
+    // Rebuild on a new commit. Only for files that exist: cargo reruns the
+    // script every build for a missing one.
+    let head = Path::new(".git/HEAD");
+    if head.exists() {
+        println!("cargo:rerun-if-changed=.git/HEAD");
+        if let Some(branch) = fs::read_to_string(head)
+            .ok()
+            .and_then(|head| head.strip_prefix("ref: ").map(|r| r.trim().to_string()))
+        {
+            let branch = Path::new(".git").join(branch);
+            if branch.exists() {
+                println!("cargo:rerun-if-changed={}", branch.display());
+            }
+        }
+    }
+}
@@ -0,0 +1,104 @@
+//! Rust API for embedding the token handling without Cyrus SASL, e.g. in
+//! an SMTP or IMAP client that does its own AUTH exchange.
+
+use crate::config::Config;
+use crate::error::XoauthError;
+use crate::log::{Log, LogMode};
+use crate::token_store::TokenStore;
+
+/// Build the XOAUTH2 response: `user=<email>\x01auth=Bearer <token>\x01\x01`.
+pub fn xoauth2_response(user: &str, token: &str) -> Vec<u8> {
+    format!("user={}\x01auth=Bearer {}\x01\x01", user, token).into_bytes()
+}
+
+/// Build the OAUTHBEARER response (RFC 7628):
+/// `n,a=<saslname>,\x01auth=Bearer <token>\x01\x01`.
+pub fn oauthbearer_response(user: &str, token: &str) -> Vec<u8> {
+    // GS2 saslname escaping (RFC 5801): ',' and '=' must be encoded.
+    let saslname = user.replace('=', "=3D").replace(',', "=2C");
+    format!("n,a={},\x01auth=Bearer {}\x01\x01", saslname, token).into_bytes()
+}
+
+/// Fresh access tokens from a token file, refreshed and written back as
+/// the plugin would.
+pub struct TokenProvider {
+    store: TokenStore,
+    log: Log,
+}
+
+impl TokenProvider {
+    /// Read the token file at `path`, logging nothing.
+    pub fn new(path: &str, config: &'static Config) -> Result<Self, XoauthError> {
+        Self::with_log(path, config, Log::new(LogMode::None))
+    }
+
+    /// Read the token file at `path`, logging to `log`.
+    pub fn with_log(path: &str, config: &'static Config, log: Log) -> Result<Self, XoauthError> {
+        let store = TokenStore::with_config(&log, path, config).ok_or(XoauthError::Io)?;
+        Ok(TokenProvider { store, log })
+    }
+
+    /// A bearer token that isn't about to expire, refreshing first if needed.
+    pub fn access_token(&mut self) -> Result<String, XoauthError> {
+        self.store.get_access_token(&self.log)
+    }
+
+    /// The user override from the token file, if set.
+    pub fn user(&self) -> Option<&str> {
+        self.store.user()
+    }
+
+    /// Why the last refresh failed at the token endpoint, if it did.
+    pub fn last_error(&self) -> Option<&str> {
+        self.store.last_error()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{fake_endpoint, test_config};
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_responses() {
+        assert_eq!(
+            xoauth2_response("user@example.com", "ya29.accesstoken"),
+            b"user=user@example.com\x01auth=Bearer ya29.accesstoken\x01\x01"
+        );
+        assert_eq!(
+            oauthbearer_response("a,b=c@example.com", "t"),
+            b"n,a=a=2Cb=3Dc@example.com,\x01auth=Bearer t\x01\x01"
+        );
+    }
+
+    #[test]
+    fn test_token_provider() {
+        let (url, server) = fake_endpoint(vec![(
+            200,
+            r#"{"access_token":"at-new","expires_in":3600}"#.to_string(),
+        )]);
+        let mut f = NamedTempFile::new().unwrap();
+        write!(
+            f,
+            r#"{{ "refresh_token": "rt", "user": "me@example.com", "token_endpoint": "{}" }}"#,
+            url
+        )
+        .unwrap();
+        let config = test_config(r#"{ "client_id": "id" }"#);
+
+        let mut provider = TokenProvider::new(f.path().to_str().unwrap(), config).unwrap();
+        assert_eq!(provider.user(), Some("me@example.com"));
+        assert_eq!(provider.access_token(), Ok("at-new".to_string()));
+        // Still fresh: no second request.
+        assert_eq!(provider.access_token(), Ok("at-new".to_string()));
+        assert_eq!(server.join().unwrap().len(), 1);
+        assert_eq!(provider.last_error(), None);
+
+        assert_eq!(
+            TokenProvider::new("/nonexistent/token.json", config).err(),
+            Some(XoauthError::Io)
+        );
+    }
+}
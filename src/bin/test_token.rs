@@ -1,81 +1,440 @@
 //! CLI tool for testing SASL XOAUTH2 token refresh without Postfix.
 //!
 //! Usage:
-//!   sasl-xoauth2-test <token-file> [--config <config-path>]
+//!   sasl-xoauth2-test [--json] <token-file> [--config <config-path>] [--dry-run]
+//!                     [--refresh-if-within <secs>]
+//!   sasl-xoauth2-test <token-dir> [--config <config-path>] [--dry-run]
+//!                     [--refresh-if-within <secs>] [--parallel <n>]
+//!   sasl-xoauth2-test <token-file> [--config <config-path>] --device-code
+//!   sasl-xoauth2-test --selftest <plugin.so> [--config <config-path>]
+//!   sasl-xoauth2-test --check-config <config-path>
+//!   sasl-xoauth2-test --version
 //!
 //! Loads the plugin configuration, reads the token file, forces a refresh,
-//! and reports success or failure.
+//! and reports success or failure. With `--dry-run` the refreshed token is
+//! not written back. With `--refresh-if-within` it only refreshes if the
+//! access token expires within that many seconds, for cron-driven
+//! proactive refresh. Given a directory, it does this for every `*.json`
+//! token file in it, `--parallel` at a time, and prints a summary. With
+//! `--device-code` it instead gets new tokens through
+//! the device authorization flow, for when the refresh token is dead. With
+//! `--json` the result is printed as one JSON object, for health checks.
+//! With `--selftest` it loads the built plugin library and runs its
+//! `sasl_xoauth2_selftest` entry point, to check the ABI when packaging.
+//! With `--check-config` it only loads and checks the config, without
+//! network access or token files, for deploy-time linting. `--version`
+//! prints the version and git commit it was built from.
 
 use std::env;
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
 
 use saslxoauth2::config::Config;
+use saslxoauth2::device_flow;
 use saslxoauth2::log::{Log, LogMode};
 use saslxoauth2::token_store::TokenStore;
 
 fn main() {
     let args: Vec<String> = env::args().collect();
 
+    if args.get(1).is_some_and(|a| a == "--version" || a == "-V") {
+        println!("sasl-xoauth2 {}", saslxoauth2::version());
+        return;
+    }
+
     if args.len() < 2 || args[1] == "--help" || args[1] == "-h" {
-        eprintln!("Usage: {} <token-file> [--config <config-path>]", args[0]);
+        eprintln!(
+            "Usage: {} [--json] <token-file> [--config <config-path>] [--dry-run | --device-code]",
+            args[0]
+        );
         eprintln!();
         eprintln!("Tests SASL XOAUTH2 token refresh without running Postfix.");
         eprintln!();
         eprintln!("Arguments:");
-        eprintln!("  <token-file>                 Path to the OAuth2 token JSON file");
-        eprintln!("  --config <path>              Config file (default: /etc/sasl-xoauth2.conf)");
+        eprintln!("  <token-file>                 Path to the OAuth2 token JSON file, or a");
+        eprintln!("                               directory to refresh every *.json file in");
+        eprintln!(
+            "  --config <path>              Config file (default: ${}, else /etc/sasl-xoauth2.conf)",
+            saslxoauth2::config::CONFIG_PATH_ENV
+        );
+        eprintln!("  --dry-run                    Refresh, but don't write the token file");
+        eprintln!("                               (a provider that rotates refresh tokens may");
+        eprintln!("                               still invalidate the one in the file)");
+        eprintln!("  --refresh-if-within <secs>   Only refresh if the access token expires");
+        eprintln!("                               within <secs>; otherwise exit 0 untouched");
+        eprintln!("  --parallel <n>               With a directory, refresh <n> files at a time");
+        eprintln!("                               (default 1)");
+        eprintln!("  --device-code                Sign in again through the device flow and");
+        eprintln!("                               write fresh tokens to the token file");
+        eprintln!("  --json                       Print one JSON object with the result instead");
+        eprintln!("                               of the human-readable report");
+        eprintln!("  --selftest <plugin.so>       Load the plugin library and run its self-test");
+        eprintln!("                               (no token file needed)");
+        eprintln!("  --check-config <path>        Check a config file and exit, without network");
+        eprintln!("                               access or token files");
+        eprintln!("  --version                    Print the version and build info");
         process::exit(1);
     }
 
-    let token_path = &args[1];
-    let config_path = if let Some(pos) = args.iter().position(|a| a == "--config") {
+    if let Some(pos) = args.iter().position(|a| a == "--check-config") {
+        let Some(path) = args.get(pos + 1) else {
+            eprintln!("Error: --check-config requires a config path");
+            process::exit(1);
+        };
+        run_check_config(path);
+        return;
+    }
+
+    let dry_run = args.iter().any(|a| a == "--dry-run");
+    let device_code = args.iter().any(|a| a == "--device-code");
+    let json = args.iter().any(|a| a == "--json");
+    let config_pos = args.iter().position(|a| a == "--config");
+    let config_path = if let Some(pos) = config_pos {
         args.get(pos + 1)
             .unwrap_or_else(|| {
                 eprintln!("Error: --config requires a path argument");
                 process::exit(1);
             })
-            .as_str()
+            .clone()
     } else {
-        "/etc/sasl-xoauth2.conf"
+        Config::path()
+    };
+    if let Some(pos) = args.iter().position(|a| a == "--selftest") {
+        let Some(library) = args.get(pos + 1) else {
+            eprintln!("Error: --selftest requires a plugin library path");
+            process::exit(1);
+        };
+        run_selftest(library, &config_path);
+        return;
+    }
+    let within_pos = args.iter().position(|a| a == "--refresh-if-within");
+    let refresh_if_within = within_pos.map(|pos| {
+        args.get(pos + 1)
+            .and_then(|secs| secs.parse::<i64>().ok())
+            .unwrap_or_else(|| {
+                eprintln!("Error: --refresh-if-within requires a number of seconds");
+                process::exit(1);
+            })
+    });
+    let parallel_pos = args.iter().position(|a| a == "--parallel");
+    let parallel = match parallel_pos {
+        Some(pos) => args
+            .get(pos + 1)
+            .and_then(|n| n.parse::<usize>().ok())
+            .filter(|&n| n > 0)
+            .unwrap_or_else(|| {
+                eprintln!("Error: --parallel requires a number of files above 0");
+                process::exit(1);
+            }),
+        None => 1,
     };
+    let flag_values = [config_pos, within_pos, parallel_pos].map(|pos| pos.map(|pos| pos + 1));
+    let token_path = args
+        .iter()
+        .enumerate()
+        .skip(1)
+        .find(|&(i, a)| !a.starts_with("--") && !flag_values.contains(&Some(i)))
+        .map(|(_, a)| a)
+        .unwrap_or_else(|| {
+            eprintln!("Error: no token file given");
+            process::exit(1);
+        });
+    if json && device_code {
+        eprintln!("Error: --json can't be used with --device-code");
+        process::exit(1);
+    }
+    if refresh_if_within.is_some() && device_code {
+        eprintln!("Error: --refresh-if-within can't be used with --device-code");
+        process::exit(1);
+    }
+    let report = Report { json };
 
     // Load config
-    println!("Loading config from: {}", config_path);
-    let err = Config::init_from_path(config_path);
+    report.line(format!("Loading config from: {}", config_path));
+    let err = Config::init_from_path(&config_path);
     if err != 0 {
-        eprintln!("Error: failed to load config from {}", config_path);
-        process::exit(1);
+        report.fail(None, format!("failed to load config from {}", config_path));
     }
 
-    let config = Config::get();
-    println!("  client_id: {}", config.client_id);
-    println!("  token_endpoint: {}", config.token_endpoint);
+    let Some(config) = Config::get() else {
+        report.fail(None, "config not initialized".to_string());
+    };
+    report.line(format!("  client_id: {}", config.client_id));
+    report.line(format!("  token_endpoint: {}", config.token_endpoint()));
+
+    if device_code {
+        run_device_flow(config, token_path);
+        return;
+    }
 
     // Load token file
+    saslxoauth2::log::init_syslog(config.syslog_facility, &config.syslog_ident);
+    if Path::new(token_path).is_dir() {
+        if json {
+            report.fail(None, "--json can't be used with a directory".to_string());
+        }
+        run_dir(token_path, dry_run, refresh_if_within, parallel);
+        return;
+    }
     let log = Log::new(LogMode::Immediate);
-    println!("\nLoading token file: {}", token_path);
+    report.line(format!("\nLoading token file: {}", token_path));
     let mut store = match TokenStore::new(&log, token_path) {
         Some(s) => s,
-        None => {
-            eprintln!("Error: failed to load token file {}", token_path);
-            process::exit(1);
-        }
+        None => report.fail(None, format!("failed to load token file {}", token_path)),
     };
 
     if let Some(user) = store.user() {
-        println!("  user: {}", user);
+        report.line(format!("  user: {}", user));
+    }
+    report.line(format!("  access token expires at: {}", store.expiry_unix()));
+    if store.access_token_valid() {
+        report.line(format!(
+            "  access token valid for another {}s",
+            store.seconds_until_expiry()
+        ));
+    } else if store.access_token_raw().is_empty() {
+        report.line("  no access token yet".to_string());
+    } else {
+        report.line("  access token expired".to_string());
+    }
+    report.line(format!("  access token length: {}", store.access_token_raw().len()));
+    report.line(format!(
+        "  plugin would refresh before use: {}",
+        if store.needs_refresh(&log) { "yes" } else { "no" }
+    ));
+
+    if let Some(secs) = refresh_if_within {
+        if !store.expires_within(secs) {
+            report.line(format!("\nNot refreshing: access token expires in more than {}s", secs));
+            report.finish(Some(&store), None);
+            return;
+        }
     }
 
     // Force refresh
-    println!("\nAttempting token refresh...");
-    match store.refresh(&log) {
-        Ok(()) => {
-            println!("Token refresh successful!");
-            println!("  New token written to: {}", token_path);
+    report.line("\nAttempting token refresh...".to_string());
+    let result = if dry_run {
+        store.refresh_in_memory(&log).map(|(_, expiry)| {
+            report.line("Token refresh successful! (dry run)".to_string());
+            report.line(format!("  New token expires at: {}", expiry));
+            report.line(format!("  Token file not modified: {}", token_path));
+        })
+    } else {
+        store.refresh(&log).map(|()| {
+            report.line("Token refresh successful!".to_string());
+            report.line(format!("  New token written to: {}", token_path));
+        })
+    };
+    if let Err(e) = result {
+        if json {
+            let error = store.last_error().unwrap_or("token refresh failed").to_string();
+            report.fail(Some(&store), error);
+        }
+        eprintln!(
+            "Token refresh failed ({:?}) with SASL error code: {}",
+            e,
+            e.to_sasl_code()
+        );
+        if let Some(reason) = store.last_error() {
+            eprintln!("  Reason: {}", reason);
+        }
+        process::exit(1);
+    }
+    report.finish(Some(&store), None);
+}
+
+/// Where the results go: human-readable lines, or with `--json` a single
+/// JSON object on stdout at the end.
+struct Report {
+    json: bool,
+}
+
+impl Report {
+    fn line(&self, line: String) {
+        if !self.json {
+            println!("{}", line);
+        }
+    }
+
+    /// Report an error and exit with status 1.
+    fn fail(&self, store: Option<&TokenStore>, error: String) -> ! {
+        if !self.json {
+            eprintln!("Error: {}", error);
+        }
+        self.finish(store, Some(error));
+        process::exit(1);
+    }
+
+    /// With `--json`, print the result object.
+    fn finish(&self, store: Option<&TokenStore>, error: Option<String>) {
+        if !self.json {
+            return;
         }
-        Err(code) => {
-            eprintln!("Token refresh failed with SASL error code: {}", code);
+        let result = serde_json::json!({
+            "ok": error.is_none(),
+            "user": store.and_then(|s| s.user()),
+            "expiry": store.map(|s| s.expiry_unix()),
+            "refreshed": store.is_some_and(|s| s.refreshed()),
+            "error": error,
+        });
+        println!("{}", result);
+    }
+}
+
+/// Refresh every `*.json` token file in `dir`, `parallel` at a time, and
+/// exit with status 1 if any failed.
+fn run_dir(dir: &str, dry_run: bool, refresh_if_within: Option<i64>, parallel: usize) {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)
+        .unwrap_or_else(|e| {
+            eprintln!("Error: reading {}: {}", dir, e);
             process::exit(1);
+        })
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json") && path.is_file())
+        .collect();
+    paths.sort();
+    println!("\nRefreshing {} token files in {}", paths.len(), dir);
+
+    // Workers take the next file until none are left.
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(Vec::new());
+    std::thread::scope(|scope| {
+        for _ in 0..parallel.min(paths.len()) {
+            scope.spawn(|| {
+                while let Some(path) = paths.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    let path = path.to_string_lossy().into_owned();
+                    let result = refresh_file(&path, dry_run, refresh_if_within);
+                    match result {
+                        Ok(ref status) => println!("  ok    {}: {}", path, status),
+                        Err(ref error) => println!("  FAIL  {}: {}", path, error),
+                    }
+                    if let Ok(mut results) = results.lock() {
+                        results.push(result.is_ok());
+                    }
+                }
+            });
+        }
+    });
+
+    let results = results.into_inner().unwrap_or_default();
+    let failed = results.iter().filter(|ok| !**ok).count();
+    println!("\n{} refreshed or current, {} failed", results.len() - failed, failed);
+    if failed > 0 {
+        process::exit(1);
+    }
+}
+
+/// Refresh one token file of a directory, describing what was done.
+fn refresh_file(
+    path: &str,
+    dry_run: bool,
+    refresh_if_within: Option<i64>,
+) -> Result<String, String> {
+    let log = Log::new(LogMode::Immediate);
+    let mut store = TokenStore::new(&log, path).ok_or("failed to load token file")?;
+    if let Some(secs) = refresh_if_within {
+        if !store.expires_within(secs) {
+            return Ok(format!("valid for another {}s", store.seconds_until_expiry()));
+        }
+    }
+    let result = match dry_run {
+        true => store.refresh_in_memory(&log).map(|_| "refreshed (dry run)"),
+        false => store.refresh(&log).map(|()| "refreshed"),
+    };
+    result.map(str::to_string).map_err(|e| match store.last_error() {
+        Some(reason) => format!("{:?} (SASL error code {}): {}", e, e.to_sasl_code(), reason),
+        None => format!("{:?} (SASL error code {})", e, e.to_sasl_code()),
+    })
+}
+
+/// Load the config at `path` and report its problems, exiting 1 if any.
+fn run_check_config(path: &str) {
+    let config = Config::load(path).unwrap_or_else(|e| {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    });
+    let problems = config.problems();
+    if problems.is_empty() {
+        println!("{}: OK", path);
+        return;
+    }
+    for problem in problems {
+        eprintln!("Error: {}: {}", path, problem);
+    }
+    process::exit(1);
+}
+
+/// Get fresh tokens through the device authorization flow.
+fn run_device_flow(config: &Config, token_path: &str) {
+    println!("\nStarting device authorization...");
+    let code = device_flow::request_device_code(config).unwrap_or_else(|e| {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    });
+    match code.message {
+        Some(ref message) => println!("{}", message),
+        None => println!(
+            "To sign in, open {} and enter the code {}",
+            code.verification_uri, code.user_code
+        ),
+    }
+
+    println!("\nWaiting for authorization...");
+    if let Err(e) = device_flow::poll_and_write(config, &code, token_path) {
+        eprintln!("Device authorization failed: {}", e);
+        process::exit(1);
+    }
+    println!("Device authorization successful!");
+    println!("  New token written to: {}", token_path);
+}
+
+/// Load the plugin library at `library` the way SASL would, and call its
+/// self-test entry point with `config_path`.
+fn run_selftest(library: &str, config_path: &str) {
+    type Selftest = unsafe extern "C" fn(*const libc::c_char) -> libc::c_int;
+    type Version = unsafe extern "C" fn() -> *const libc::c_char;
+
+    let fail = |msg: String| -> ! {
+        eprintln!("Error: {}", msg);
+        process::exit(1);
+    };
+    let dlerror = || unsafe {
+        let e = libc::dlerror();
+        match e.is_null() {
+            true => "unknown error".to_string(),
+            false => CStr::from_ptr(e).to_string_lossy().into_owned(),
         }
+    };
+    let c_library = CString::new(library).unwrap_or_else(|e| fail(e.to_string()));
+    let c_config = CString::new(config_path).unwrap_or_else(|e| fail(e.to_string()));
+
+    println!("Loading plugin: {}", library);
+    let handle = unsafe { libc::dlopen(c_library.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+    if handle.is_null() {
+        fail(format!("dlopen {}: {}", library, dlerror()));
+    }
+    for symbol in [c"sasl_client_plug_init", c"sasl_xoauth2_selftest"] {
+        if unsafe { libc::dlsym(handle, symbol.as_ptr()) }.is_null() {
+            fail(format!("{} not exported: {}", symbol.to_string_lossy(), dlerror()));
+        }
+    }
+    let selftest: Selftest =
+        unsafe { std::mem::transmute(libc::dlsym(handle, c"sasl_xoauth2_selftest".as_ptr())) };
+    let version = unsafe { libc::dlsym(handle, c"sasl_xoauth2_version".as_ptr()) };
+    if !version.is_null() {
+        let version: Version = unsafe { std::mem::transmute(version) };
+        let version = unsafe { CStr::from_ptr(version()) };
+        println!("Plugin version: {}", version.to_string_lossy());
+    }
+
+    println!("Running self-test with config: {}", config_path);
+    let err = unsafe { selftest(c_config.as_ptr()) };
+    if err != 0 {
+        fail(format!("self-test failed with SASL error code: {}", err));
     }
+    println!("Self-test passed");
 }
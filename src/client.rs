@@ -1,36 +1,107 @@
-//! XOAUTH2 client state machine — the core SASL mechanism logic.
+//! XOAUTH2/OAUTHBEARER client state machine — the core SASL mechanism logic.
 //!
-//! Implements the two-step XOAUTH2 protocol:
+//! Implements the two-step bearer token protocol shared by both mechanisms:
 //! 1. InitialStep: extract user + token path from SASL callbacks, send bearer token
-//! 2. TokenSentStep: handle server response, retry on 401/400
+//! 2. TokenSentStep: handle server response, retry when the token is rejected
+//!
+//! Once the exchange is done or has failed, further steps repeat that outcome.
 
+use base64::prelude::*;
 use libc::{c_char, c_int, c_uint, c_ulong, c_void};
+use std::collections::BTreeMap;
+use std::ffi::{CStr, CString};
 use std::ptr;
 use std::slice;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
-use crate::config::Config;
+use crate::api::{oauthbearer_response, xoauth2_response};
+use crate::config::{Config, TokenMode, TokenPathSource};
+use crate::error::XoauthError;
 use crate::ffi::*;
-use crate::log::{Log, LogMode};
-use crate::token_store::TokenStore;
+use crate::log::{redact, Log, LogLevel, LogMode};
+use crate::token_store::{self, TokenStore};
+
+/// SASL mechanism a client was instantiated for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mechanism {
+    /// Google's XOAUTH2: `user=<email>\x01auth=Bearer <token>\x01\x01`.
+    XOAuth2,
+    /// RFC 7628 OAUTHBEARER, with a GS2 header in place of `user=`.
+    OAuthBearer,
+}
+
+impl Mechanism {
+    /// The mechanism name as registered with SASL.
+    pub const fn name(self) -> &'static CStr {
+        match self {
+            Mechanism::XOAuth2 => c"XOAUTH2",
+            Mechanism::OAuthBearer => c"OAUTHBEARER",
+        }
+    }
+
+    /// Whether the server's error `status` means the bearer token was
+    /// rejected, so a refresh is worth trying.
+    fn is_token_rejected(self, status: &str) -> bool {
+        match self {
+            Mechanism::XOAuth2 => status == "400" || status == "401",
+            // RFC 7628 reports OAuth error codes; some servers still send
+            // HTTP-style codes as with XOAUTH2.
+            Mechanism::OAuthBearer => matches!(status, "invalid_token" | "400" | "401"),
+        }
+    }
+
+    /// Build the initial client response carrying `token` for `user`.
+    fn initial_response(self, user: &str, token: &str) -> Vec<u8> {
+        match self {
+            Mechanism::XOAuth2 => xoauth2_response(user, token),
+            Mechanism::OAuthBearer => oauthbearer_response(user, token),
+        }
+    }
+}
 
 #[derive(Debug, PartialEq)]
 enum State {
     Initial,
     TokenSent,
+    /// The server sent a blank reply; with `confirm_blank_status`, one
+    /// more turn decides the outcome.
+    Confirming,
+    /// The server accepted the token.
+    Done,
+    /// The exchange ended in an error.
+    Failed,
 }
 
 pub struct Client {
+    mechanism: Mechanism,
     state: State,
     user: String,
     response: Vec<u8>, // kept alive so the pointer we return to SASL remains valid
     log: Log,
     token: Option<TokenStore>,
+    /// Pre-minted access token used in `read_only_access_token` mode.
+    fixed_token: Option<String>,
+    config: &'static Config,
+    step_deadline: Option<Instant>,
+    /// The SMTP server's FQDN as given by SASL, empty if not given.
+    server_fqdn: String,
+    /// `SASL_TRYAGAIN`s already returned in a row for this token file after
+    /// the server rejected its access token.
+    retry_count: u32,
 }
 
 impl Client {
-    pub fn new() -> Self {
-        let config = Config::get();
-        let mode = if config.always_log_to_syslog {
+    /// Create a client using the global config, or `None` if it isn't
+    /// initialized.
+    pub fn new(mechanism: Mechanism) -> Option<Self> {
+        Config::get().map(|config| Self::with_config(config, mechanism))
+    }
+
+    pub fn with_config(config: &'static Config, mechanism: Mechanism) -> Self {
+        let mode = if config.log_json_to_stderr {
+            LogMode::JsonStderr
+        } else if config.always_log_to_syslog {
             LogMode::Immediate
         } else if config.log_full_trace_on_failure {
             LogMode::FullTraceOnFailure
@@ -40,15 +111,21 @@ impl Client {
             LogMode::None
         };
 
-        let log = Log::new(mode);
-        log.write("Client: created");
+        let log = Log::with_level(mode, config.log_level);
+        log.write(format!("Client: created for {:?}", mechanism));
 
         Self {
+            mechanism,
             state: State::Initial,
             user: String::new(),
             response: Vec::new(),
             log,
             token: None,
+            fixed_token: None,
+            config,
+            step_deadline: None,
+            server_fqdn: String::new(),
+            retry_count: 0,
         }
     }
 
@@ -63,204 +140,738 @@ impl Client {
         to_server_len: *mut c_uint,
         out_params: *mut sasl_out_params_t,
     ) -> c_int {
+        let from_server = match from_server.is_null() {
+            true => &[][..],
+            false => slice::from_raw_parts(from_server as *const u8, from_server_len as usize),
+        };
+        let mut env = FfiEnv::new(params, prompt_need, out_params);
+        let (err, response) = self.step(&mut env, from_server);
+        match response {
+            Some(response) => {
+                *to_server = response.as_ptr() as *const c_char;
+                *to_server_len = response.len() as c_uint;
+            }
+            None => {
+                *to_server = ptr::null();
+                *to_server_len = 0;
+            }
+        }
+        err
+    }
+
+    /// One protocol step: the SASL result code, and the response for the
+    /// server if this step sends one.
+    fn step(&mut self, env: &mut impl SaslEnv, from_server: &[u8]) -> (c_int, Option<&[u8]>) {
         self.log
-            .write(format!("Client::do_step: state={:?}", self.state));
+            .write_level(LogLevel::Debug, format!("Client::do_step: state={:?}", self.state));
+
+        // Bound how long this step may block on token refresh.
+        self.step_deadline = match self.config.max_step_block_time {
+            0 => None,
+            secs => Some(Instant::now() + Duration::from_secs(secs)),
+        };
+        if let Some(ref mut store) = self.token {
+            store.set_deadline(self.step_deadline);
+        }
 
+        let was_initial = self.state == State::Initial;
+        let was_finished = self.is_finished();
         let err = match self.state {
             State::Initial => {
-                self.initial_step(params, prompt_need, to_server, to_server_len, out_params)
+                match self.initial_step(env) {
+                    Ok(code) => code,
+                    Err(e) => {
+                        self.state = State::Failed;
+                        e.to_sasl_code()
+                    }
+                }
+            }
+            State::TokenSent | State::Confirming => {
+                match self.token_sent_step(from_server) {
+                    Ok(SASL_CONTINUE) => {
+                        self.state = State::Confirming;
+                        SASL_CONTINUE
+                    }
+                    Ok(code) => {
+                        self.state = State::Done;
+                        self.set_retry_count(0);
+                        env.announce_version();
+                        code
+                    }
+                    Err(e) => {
+                        self.state = State::Failed;
+                        e.to_sasl_code()
+                    }
+                }
+            }
+            // SASL may call again after the exchange ended; don't reinterpret
+            // server data, just repeat the outcome.
+            State::Done | State::Failed => {
+                self.log.write("Client::do_step: called after exchange completed");
+                if self.state == State::Done {
+                    SASL_OK
+                } else {
+                    SASL_FAIL
+                }
             }
-            State::TokenSent => self.token_sent_step(
-                params,
-                from_server,
-                from_server_len,
-                to_server,
-                to_server_len,
-            ),
         };
 
+        if !was_finished && self.is_finished() {
+            self.emit_result(err);
+        }
+
         if err != SASL_OK && err != SASL_INTERACT {
             self.log.set_flush_on_destroy();
         }
         self.log
-            .write(format!("Client::do_step: new state={:?}, err={}", self.state, err));
-        err
+            .write_level(
+                LogLevel::Debug,
+                format!("Client::do_step: new state={:?}, err={}", self.state, err),
+            );
+        let sent = was_initial && self.state == State::TokenSent;
+        (err, sent.then_some(self.response.as_slice()))
     }
 
-    unsafe fn initial_step(
-        &mut self,
-        params: *mut sasl_client_params_t,
-        prompt_need: *mut *mut sasl_interact_t,
-        to_server: *mut *const c_char,
-        to_server_len: *mut c_uint,
-        out_params: *mut sasl_out_params_t,
-    ) -> c_int {
-        *to_server = ptr::null();
-        *to_server_len = 0;
+    fn is_finished(&self) -> bool {
+        matches!(self.state, State::Done | State::Failed)
+    }
+
+    /// Log the exchange's outcome as one greppable line, if configured:
+    /// `RESULT user=.. mechanism=.. result=ok|fail|tryagain refreshed=..`,
+    /// plus `error=..` on failure.
+    fn emit_result(&self, err: c_int) {
+        if !self.config.emit_result_line {
+            return;
+        }
+        let result = match err {
+            SASL_OK => "ok",
+            SASL_TRYAGAIN => "tryagain",
+            _ => "fail",
+        };
+        let refreshed = self.token.as_ref().is_some_and(|t| t.refreshed());
+        let mut line = format!(
+            "RESULT user={} mechanism={} result={} refreshed={}",
+            self.user,
+            self.mechanism.name().to_string_lossy(),
+            result,
+            refreshed
+        );
+        if err != SASL_OK {
+            line.push_str(&format!(" error={}", error_class(err)));
+        }
+        self.log.write_always(line);
+    }
 
-        let p = &*params;
-        let utils = &*p.utils;
+    /// Send the initial response, returning `SASL_OK`, or `SASL_INTERACT`
+    /// when prompts are needed first.
+    fn initial_step(&mut self, env: &mut impl SaslEnv) -> Result<c_int, XoauthError> {
+        if let Some(fqdn) = env.server_fqdn() {
+            self.server_fqdn = fqdn;
+            self.log
+                .write(format!("initial_step: serverFQDN='{}'", self.server_fqdn));
+        }
+
+        // Answers to prompts we asked for in an earlier call, if any.
+        let answered_prompts = env.has_prompt_answers();
 
         // Try to get auth name from prompts, then from callback
-        let mut auth_name = String::new();
-        if !prompt_need.is_null() && !(*prompt_need).is_null() {
-            auth_name = read_prompt(*prompt_need, SASL_CB_AUTHNAME);
-        }
+        let mut auth_name = env.prompt_answer(SASL_CB_AUTHNAME);
         if auth_name.is_empty() {
-            if let Some(name) = trigger_auth_name_callback(utils) {
+            if let Some(name) = env.callback(SASL_CB_AUTHNAME) {
                 auth_name = name;
             }
         }
+        self.log.set_user(&auth_name);
         self.log
             .write(format!("initial_step: auth_name='{}'", auth_name));
 
-        // Try to get password (token file path) from prompts, then from callback
-        let mut password = String::new();
-        if !prompt_need.is_null() && !(*prompt_need).is_null() {
-            password = read_prompt(*prompt_need, SASL_CB_PASS);
-        }
-        if password.is_empty() {
-            if let Some(pass) = trigger_password_callback(utils) {
-                password = pass;
+        // An authorization id different from the auth name (e.g. a shared
+        // mailbox) is optional, so it's never prompted for.
+        let mut authz_id = env.prompt_answer(SASL_CB_USER);
+        if authz_id.is_empty() {
+            if let Some(name) = env.callback(SASL_CB_USER) {
+                authz_id = name;
             }
         }
-        self.log
-            .write(format!("initial_step: password/path='{}'", password));
+        if !authz_id.is_empty() && authz_id != auth_name {
+            self.log.write(format!("initial_step: authz_id='{}'", authz_id));
+        }
 
-        // Free any previous prompts
-        if !prompt_need.is_null() && !(*prompt_need).is_null() {
-            if let Some(free_fn) = utils.free {
-                free_fn(*prompt_need as *mut c_void);
+        // Get the token file path, by default from the password (prompts,
+        // then callback), or from a named SASL property if so configured.
+        let (password, path_from_password) = match self.config.token_path_source {
+            TokenPathSource::Password => {
+                let mut password = env.prompt_answer(SASL_CB_PASS);
+                if password.is_empty() {
+                    if let Some(pass) = env.callback(SASL_CB_PASS) {
+                        password = pass;
+                    }
+                }
+                // A misconfigured password map may hand us a secret rather
+                // than a path.
+                self.log
+                    .write(format!("initial_step: password/path='{}'", redact(&password)));
+                (password, true)
             }
-            *prompt_need = ptr::null_mut();
-        }
+            TokenPathSource::Property(ref name) => {
+                // The password stays free for its usual purpose; nothing
+                // else can stand in for the path.
+                let Some(path) = env.property(self.mechanism, name) else {
+                    env.free_prompts();
+                    self.log
+                        .write(format!("initial_step: property '{}' not set", name));
+                    return Err(XoauthError::MissingField("token path property"));
+                };
+                self.log.write(format!(
+                    "initial_step: property '{}' path='{}'",
+                    name,
+                    redact(&path)
+                ));
+                (path, false)
+            }
+        };
 
-        // If we still need info, request prompts
-        if !prompt_need.is_null() && (auth_name.is_empty() || password.is_empty()) {
-            self.log.write("initial_step: need prompts, returning SASL_INTERACT");
-            return request_prompts(
-                params,
-                prompt_need,
-                auth_name.is_empty(),
-                password.is_empty(),
+        // Free any previous prompts
+        env.free_prompts();
+
+        // If we still need info, request prompts, but only once: asking
+        // again after a partial answer could loop forever.
+        let need_password = path_from_password && password.is_empty();
+        if answered_prompts && (auth_name.is_empty() || need_password) {
+            let missing = match (auth_name.is_empty(), need_password) {
+                (true, true) => "auth name and password",
+                (true, false) => "auth name",
+                _ => "password",
+            };
+            self.log.write_level(
+                LogLevel::Error,
+                format!("initial_step: prompts answered without the {}", missing),
             );
+            return Err(XoauthError::MissingField(missing));
+        }
+        if env.can_prompt() && (auth_name.is_empty() || need_password) {
+            self.log.write("initial_step: need prompts, returning SASL_INTERACT");
+            return match env.request_prompts(auth_name.is_empty(), need_password) {
+                SASL_INTERACT => Ok(SASL_INTERACT),
+                err => Err(XoauthError::Sasl(err)),
+            };
         }
 
-        // Canonicalize user
-        if let Some(canon_user) = p.canon_user {
-            let err = canon_user(
-                utils.conn,
-                auth_name.as_ptr() as *const c_char,
-                auth_name.len() as c_uint,
-                (SASL_CU_AUTHID | SASL_CU_AUTHZID) as c_uint,
-                out_params,
-            );
+        if authz_id.is_empty() {
+            authz_id = auth_name.clone();
+        }
+        self.canonicalize_user(env, &auth_name, &authz_id)?;
+
+        self.user = authz_id.clone();
+
+        // Like the log line above: the password may be a secret, not a path.
+        self.log.set_token_path(&if path_from_password {
+            redact(&password)
+        } else {
+            password.clone()
+        });
+        self.load_token(&password)?;
+
+        // Keep the identity SASL recorded consistent with the user= we send.
+        if self.user != authz_id && self.config.recanonicalize_user {
+            self.log.write(format!(
+                "initial_step: user changed to '{}', re-canonicalizing",
+                self.user
+            ));
+            let user = self.user.clone();
+            self.log.set_user(&user);
+            self.canonicalize_user(env, &user, &user)?;
+        }
+
+        self.send_token()?;
+        env.set_no_security_layer();
+
+        self.state = State::TokenSent;
+        Ok(SASL_OK)
+    }
+
+    /// Canonicalize the authentication and authorization ids, in one call
+    /// when they're the same user.
+    fn canonicalize_user(
+        &self,
+        env: &mut impl SaslEnv,
+        auth_id: &str,
+        authz_id: &str,
+    ) -> Result<(), XoauthError> {
+        let ids = if auth_id == authz_id {
+            vec![(auth_id, SASL_CU_AUTHID | SASL_CU_AUTHZID)]
+        } else {
+            vec![(auth_id, SASL_CU_AUTHID), (authz_id, SASL_CU_AUTHZID)]
+        };
+        for (user, flags) in ids {
+            let err = env.canon_user(user, flags as c_uint);
             if err != SASL_OK {
                 self.log
                     .write(format!("initial_step: canon_user failed: {}", err));
-                return err;
+                return Err(XoauthError::Sasl(err));
             }
         }
+        Ok(())
+    }
 
-        self.user = auth_name;
+    /// Load the token file at `path`: a full token store, or just the access
+    /// token in `read_only_access_token` mode.
+    fn load_token(&mut self, path: &str) -> Result<(), XoauthError> {
+        if self.config.mode == TokenMode::ReadOnlyAccessToken {
+            return match token_store::read_access_token(&self.log, path) {
+                Some(t) => {
+                    self.fixed_token = Some(t);
+                    Ok(())
+                }
+                None => {
+                    self.log
+                        .write(format!("initial_step: no access token in '{}'", path));
+                    Err(XoauthError::Io)
+                }
+            };
+        }
 
-        // Password field contains the path to the token file
-        let store = match TokenStore::new(&self.log, &password) {
+        let mut store = match TokenStore::with_config(&self.log, path, self.config) {
             Some(s) => s,
             None => {
                 self.log
-                    .write(format!("initial_step: TokenStore::new failed for path '{}'", password));
-                return SASL_FAIL;
+                    .write(format!("initial_step: TokenStore::new failed for path '{}'", path));
+                return Err(XoauthError::Io);
             }
         };
 
         // If token file has a user override, use that
         if let Some(token_user) = store.user() {
-            self.user = token_user.to_string();
+            if self.config.enforce_user_match
+                && !self.config.user_match.matches(&self.user, token_user)
+            {
+                self.log.write(format!(
+                    "initial_step: token file user '{}' does not match '{}' ({:?})",
+                    token_user, self.user, self.config.user_match
+                ));
+                return Err(XoauthError::Config);
+            }
+            if self.config.prefer_sasl_authname {
+                self.log.write(format!(
+                    "initial_step: using SASL user '{}', ignoring token file user '{}'",
+                    self.user, token_user
+                ));
+            } else {
+                self.log.write(format!(
+                    "initial_step: using token file user '{}' over SASL user '{}'",
+                    token_user, self.user
+                ));
+                self.user = token_user.to_string();
+            }
         }
-
-        self.token = Some(store);
-        let err = self.send_token(to_server, to_server_len);
-        if err != SASL_OK {
-            return err;
+        store.set_deadline(self.step_deadline);
+        self.retry_count = token_retries(path);
+        if !self.config.server_fqdn_param.is_empty() {
+            if self.server_fqdn.is_empty() {
+                self.log.write_level(
+                    LogLevel::Warn,
+                    "initial_step: server_fqdn_param is set but SASL gave no server FQDN",
+                );
+            } else {
+                store.set_server_fqdn(&self.server_fqdn);
+            }
         }
 
-        self.state = State::TokenSent;
-        SASL_OK
+        self.token = Some(store);
+        Ok(())
     }
 
-    unsafe fn token_sent_step(
-        &mut self,
-        _params: *mut sasl_client_params_t,
-        from_server: *const c_char,
-        from_server_len: c_uint,
-        to_server: *mut *const c_char,
-        to_server_len: *mut c_uint,
-    ) -> c_int {
-        *to_server = ptr::null();
-        *to_server_len = 0;
-
-        if from_server_len == 0 {
-            return SASL_OK;
+    /// Handle the server's reply to the token, returning `SASL_OK`, or
+    /// `SASL_CONTINUE` to wait for the server to confirm a blank reply.
+    fn token_sent_step(&mut self, from_server: &[u8]) -> Result<c_int, XoauthError> {
+        if from_server.is_empty() {
+            return Ok(self.blank_reply());
         }
 
-        let server_data =
-            slice::from_raw_parts(from_server as *const u8, from_server_len as usize);
-        let server_str = String::from_utf8_lossy(server_data);
+        let server_str = String::from_utf8_lossy(from_server);
         self.log
-            .write(format!("Client::token_sent_step: from server: {}", server_str));
+            .write_level(
+                LogLevel::Debug,
+                format!("Client::token_sent_step: from server: {}", server_str),
+            );
 
         // Try to parse as JSON and check status
         if let Ok(json) = serde_json::from_str::<serde_json::Value>(&server_str) {
             if let Some(status) = json.get("status").and_then(|v| v.as_str()) {
-                if status == "400" || status == "401" {
+                if self.config.success_status.iter().any(|s| s == status) {
+                    self.log
+                        .write(format!("Client::token_sent_step: status {}, OK", status));
+                    return Ok(SASL_OK);
+                }
+
+                self.log_server_scope(&json);
+
+                if self.mechanism.is_token_rejected(status) {
                     // Token was rejected, try refreshing
                     if let Some(ref mut store) = self.token {
-                        if let Err(e) = store.refresh(&self.log) {
-                            return e;
+                        if self.retry_count >= self.config.max_token_retries {
+                            self.log.write(format!(
+                                "Client::token_sent_step: status {} after {} refreshes, giving up",
+                                status, self.retry_count
+                            ));
+                            set_token_retries(store.path(), 0);
+                            return Err(XoauthError::Rejected);
                         }
-                        return SASL_TRYAGAIN;
+                        if status == "401" {
+                            if let Some(aud) = jwt_audience(store.access_token_raw()) {
+                                self.log.write(format!(
+                                    "Client::token_sent_step: rejected token audience was '{}', \
+                                     check it matches the mail resource",
+                                    aud
+                                ));
+                            }
+                        }
+                        store.refresh(&self.log)?;
+                        self.retry_count += 1;
+                        set_token_retries(store.path(), self.retry_count);
+                        return Err(XoauthError::Expired);
                     }
                 }
 
                 if !status.is_empty() {
                     self.log
                         .write(format!("Client::token_sent_step: status: {}", status));
-                    return SASL_BADPROT;
+                    return Err(XoauthError::Parse);
                 }
             }
         }
 
         // Blank status or non-JSON — assume success
+        Ok(self.blank_reply())
+    }
+
+    /// Set `retry_count`, and the count the next `Client` for this token
+    /// file starts from.
+    fn set_retry_count(&mut self, count: u32) {
+        self.retry_count = count;
+        if let Some(ref store) = self.token {
+            set_token_retries(store.path(), count);
+        }
+    }
+
+    /// The outcome of a blank or non-JSON server reply: success, unless
+    /// `confirm_blank_status` asks for one more server turn first.
+    fn blank_reply(&self) -> c_int {
+        if self.config.confirm_blank_status && self.state == State::TokenSent {
+            self.log
+                .write("Client::token_sent_step: blank status, waiting for the server to confirm");
+            return SASL_CONTINUE;
+        }
         self.log
             .write("Client::token_sent_step: blank status, assuming OK");
         SASL_OK
     }
 
-    unsafe fn send_token(
-        &mut self,
-        to_server: *mut *const c_char,
-        to_server_len: *mut c_uint,
-    ) -> c_int {
-        let token = match self.token {
-            Some(ref mut store) => match store.get_access_token(&self.log) {
-                Ok(t) => t,
-                Err(e) => return e,
-            },
-            None => return SASL_FAIL,
+    /// Log the `scope` and `schemes` of the server's error response, with a
+    /// hint if it wants a scope that wasn't requested: the usual reason a
+    /// token works for IMAP but not SMTP.
+    fn log_server_scope(&self, json: &serde_json::Value) {
+        let field = |name| json.get(name).and_then(|v| v.as_str());
+        if let Some(schemes) = field("schemes") {
+            self.log
+                .write(format!("Client::token_sent_step: server schemes: {}", schemes));
+        }
+        let Some(scope) = field("scope") else {
+            return;
+        };
+        self.log
+            .write(format!("Client::token_sent_step: server scope: {}", scope));
+
+        let requested = match self.token {
+            Some(ref store) => store.scope(),
+            None => self.config.scope(),
+        };
+        let missing = unrequested_scopes(scope, requested);
+        if !missing.is_empty() {
+            self.log.write_level(
+                LogLevel::Warn,
+                format!(
+                    "Client::token_sent_step: the token likely lacks scope '{}', which the \
+                     server wants but was not requested (scope '{}'); add it to scope, and \
+                     sign in again if the refresh token wasn't granted it",
+                    missing.join(" "),
+                    requested
+                ),
+            );
+        }
+    }
+
+    /// Build the initial response in `response`.
+    fn send_token(&mut self) -> Result<(), XoauthError> {
+        let token = match (&mut self.token, &self.fixed_token) {
+            (Some(store), _) => store.get_access_token(&self.log)?,
+            (None, Some(t)) => t.clone(),
+            (None, None) => return Err(XoauthError::NoToken),
         };
 
-        // Build XOAUTH2 response: user=<email>\x01auth=Bearer <token>\x01\x01
-        self.response = format!("user={}\x01auth=Bearer {}\x01\x01", self.user, token)
-            .into_bytes();
+        for (what, value) in [("user", self.user.as_str()), ("access token", token.as_str())] {
+            if let Some(c) = value.chars().find(|c| FRAMING_CHARS.contains(c)) {
+                self.log.write_level(
+                    LogLevel::Error,
+                    format!("Client::send_token: {} contains forbidden character {:?}", what, c),
+                );
+                return Err(XoauthError::InvalidField(what));
+            }
+        }
+
+        self.response = self.mechanism.initial_response(&self.user, &token);
+        if self.config.base64_initial_response {
+            self.response = BASE64_STANDARD.encode(&self.response).into_bytes();
+        }
 
         self.log
-            .write(format!("Client::send_token: response len={}", self.response.len()));
+            .write_level(
+                LogLevel::Debug,
+                format!("Client::send_token: response len={}", self.response.len()),
+            );
+        Ok(())
+    }
+}
 
-        *to_server = self.response.as_ptr() as *const c_char;
-        *to_server_len = self.response.len() as c_uint;
+/// Characters that would corrupt the initial response's framing if they
+/// appeared in the user or token.
+const FRAMING_CHARS: [char; 4] = ['\x01', '\0', '\r', '\n'];
 
-        SASL_OK
+/// Auxiliary property reporting the plugin version to the MTA on each
+/// successful auth.
+const VERSION_PROPERTY: &CStr = c"xoauth2_plugin_version";
+
+/// Best-effort: set the plugin version as an auxiliary property of the
+/// connection, so the MTA can record which build served each auth. Without
+/// a property context or the prop functions, nothing is set.
+unsafe fn announce_version(utils: &sasl_utils_t, propctx: *mut propctx) {
+    let (Some(prop_request), Some(prop_set)) = (utils.prop_request, utils.prop_set) else {
+        return;
+    };
+    if propctx.is_null() {
+        return;
+    }
+    // SASL only sets requested properties.
+    let mut names = [VERSION_PROPERTY.as_ptr(), ptr::null()];
+    if prop_request(propctx, names.as_mut_ptr()) != SASL_OK {
+        return;
+    }
+    let version = env!("CARGO_PKG_VERSION");
+    prop_set(
+        propctx,
+        VERSION_PROPERTY.as_ptr(),
+        version.as_ptr() as *const c_char,
+        version.len() as c_int,
+    );
+}
+
+/// Record in `out_params` that there is no security layer, rather than
+/// leaving it to SASL to have zeroed them.
+unsafe fn set_no_security_layer(out_params: *mut sasl_out_params_t) {
+    if out_params.is_null() {
+        return;
+    }
+    let out = &mut *out_params;
+    out.mech_ssf = 0;
+    out.maxoutbuf = 0;
+    out.encode_context = ptr::null_mut();
+    out.encode = None;
+    out.decode_context = ptr::null_mut();
+    out.decode = None;
+}
+
+/// Short name of a SASL result code, for the `RESULT` line.
+fn error_class(err: c_int) -> String {
+    match err {
+        SASL_FAIL => "fail".to_string(),
+        SASL_BADPROT => "badprot".to_string(),
+        SASL_BADAUTH => "badauth".to_string(),
+        SASL_TRYAGAIN => "tryagain".to_string(),
+        SASL_BADPARAM => "badparam".to_string(),
+        SASL_NOMEM => "nomem".to_string(),
+        _ => format!("sasl_{}", err),
+    }
+}
+
+/// `SASL_TRYAGAIN`s returned in a row per token file, for
+/// `max_token_retries`. The MTA retries with a new `Client`, so the count
+/// has to outlive each one.
+static TOKEN_RETRIES: Mutex<BTreeMap<String, u32>> = Mutex::new(BTreeMap::new());
+
+fn token_retries(path: &str) -> u32 {
+    TOKEN_RETRIES
+        .lock()
+        .ok()
+        .and_then(|retries| retries.get(path).copied())
+        .unwrap_or(0)
+}
+
+fn set_token_retries(path: &str, count: u32) {
+    if let Ok(mut retries) = TOKEN_RETRIES.lock() {
+        if count == 0 {
+            retries.remove(path);
+        } else {
+            retries.insert(path.to_string(), count);
+        }
+    }
+}
+
+/// The scopes in `advertised` that aren't in `requested`, both
+/// space-separated.
+fn unrequested_scopes<'a>(advertised: &'a str, requested: &str) -> Vec<&'a str> {
+    advertised
+        .split_whitespace()
+        .filter(|scope| !requested.split_whitespace().any(|r| r == *scope))
+        .collect()
+}
+
+/// Decode the `aud` claim of a JWT access token, without verifying it.
+/// Returns `None` for opaque tokens. A list audience is joined with ", ".
+fn jwt_audience(token: &str) -> Option<String> {
+    match token_store::jwt_claims(token)?.get("aud")? {
+        serde_json::Value::String(aud) => Some(aud.clone()),
+        serde_json::Value::Array(auds) => Some(
+            auds.iter()
+                .filter_map(|a| a.as_str())
+                .collect::<Vec<_>>()
+                .join(", "),
+        ),
+        _ => None,
+    }
+}
+
+/// What a step needs from SASL: prompts, callbacks, options and
+/// canonicalization. `FfiEnv` does it through the structs SASL passes in;
+/// tests can use a fake.
+trait SaslEnv {
+    /// The SMTP server's FQDN, if SASL gave one.
+    fn server_fqdn(&self) -> Option<String>;
+    /// Whether this step carries answers to prompts from an earlier one.
+    fn has_prompt_answers(&self) -> bool;
+    /// The answer to prompt `id`, empty if there is none.
+    fn prompt_answer(&self, id: c_int) -> String;
+    /// The application's value for callback `id`: the auth name, the
+    /// authorization id, or the password.
+    fn callback(&self, id: c_int) -> Option<String>;
+    /// A named SASL option for `mechanism`.
+    fn property(&self, mechanism: Mechanism, name: &str) -> Option<String>;
+    /// Whether the application can be asked for prompts.
+    fn can_prompt(&self) -> bool;
+    /// Ask for the auth name and/or password: `SASL_INTERACT`, or an error.
+    fn request_prompts(&mut self, need_auth_name: bool, need_password: bool) -> c_int;
+    /// Free answered prompts.
+    fn free_prompts(&mut self);
+    /// Canonicalize `user` as the auth and/or authorization id (`flags`).
+    fn canon_user(&mut self, user: &str, flags: c_uint) -> c_int;
+    fn set_no_security_layer(&mut self);
+    /// Report the plugin version once the exchange succeeds.
+    fn announce_version(&mut self);
+}
+
+/// `SaslEnv` over the pointers SASL passes to `mech_step`.
+struct FfiEnv {
+    params: *mut sasl_client_params_t,
+    prompt_need: *mut *mut sasl_interact_t,
+    out_params: *mut sasl_out_params_t,
+    /// The connection's auxiliary property context; null without one.
+    propctx: *mut propctx,
+}
+
+impl FfiEnv {
+    /// The pointers must be valid (or null) for as long as this is used.
+    unsafe fn new(
+        params: *mut sasl_client_params_t,
+        prompt_need: *mut *mut sasl_interact_t,
+        out_params: *mut sasl_out_params_t,
+    ) -> Self {
+        let propctx = match params.as_ref().and_then(|params| params.utils.as_ref()) {
+            Some(utils) => auxprop_context(utils),
+            None => ptr::null_mut(),
+        };
+        FfiEnv { params, prompt_need, out_params, propctx }
+    }
+
+    fn utils(&self) -> Option<&sasl_utils_t> {
+        unsafe { self.params.as_ref()?.utils.as_ref() }
+    }
+}
+
+impl SaslEnv for FfiEnv {
+    fn server_fqdn(&self) -> Option<String> {
+        let params = unsafe { self.params.as_ref()? };
+        if params.serverFQDN.is_null() {
+            return None;
+        }
+        Some(unsafe { CStr::from_ptr(params.serverFQDN) }.to_string_lossy().into_owned())
+    }
+
+    fn has_prompt_answers(&self) -> bool {
+        !self.prompt_need.is_null() && unsafe { !(*self.prompt_need).is_null() }
+    }
+
+    fn prompt_answer(&self, id: c_int) -> String {
+        match self.has_prompt_answers() {
+            true => unsafe { read_prompt(*self.prompt_need, id) },
+            false => String::new(),
+        }
+    }
+
+    fn callback(&self, id: c_int) -> Option<String> {
+        let utils = self.utils()?;
+        match id {
+            SASL_CB_PASS => unsafe { trigger_password_callback(utils) },
+            _ => unsafe { trigger_simple_callback(utils, id) },
+        }
+    }
+
+    fn property(&self, mechanism: Mechanism, name: &str) -> Option<String> {
+        unsafe { read_property(self.utils()?, self.propctx, mechanism, name) }
+    }
+
+    fn can_prompt(&self) -> bool {
+        !self.prompt_need.is_null()
+    }
+
+    fn request_prompts(&mut self, need_auth_name: bool, need_password: bool) -> c_int {
+        unsafe { request_prompts(self.params, self.prompt_need, need_auth_name, need_password) }
+    }
+
+    fn free_prompts(&mut self) {
+        if !self.has_prompt_answers() {
+            return;
+        }
+        if let Some(free_fn) = self.utils().and_then(|utils| utils.free) {
+            unsafe { free_fn(*self.prompt_need as *mut c_void) };
+        }
+        unsafe { *self.prompt_need = ptr::null_mut() };
+    }
+
+    fn canon_user(&mut self, user: &str, flags: c_uint) -> c_int {
+        let Some(params) = (unsafe { self.params.as_ref() }) else {
+            return SASL_OK;
+        };
+        let (Some(canon_user), Some(utils)) = (params.canon_user, self.utils()) else {
+            return SASL_OK;
+        };
+        unsafe {
+            canon_user(
+                utils.conn,
+                user.as_ptr() as *const c_char,
+                user.len() as c_uint,
+                flags,
+                self.out_params,
+            )
+        }
+    }
+
+    fn set_no_security_layer(&mut self) {
+        unsafe { set_no_security_layer(self.out_params) }
+    }
+
+    fn announce_version(&mut self) {
+        if let Some(utils) = self.utils() {
+            unsafe { announce_version(utils, self.propctx) }
+        }
     }
 }
 
@@ -286,13 +897,13 @@ unsafe fn read_prompt(prompts: *mut sasl_interact_t, id: c_int) -> String {
     String::new()
 }
 
-unsafe fn trigger_auth_name_callback(utils: &sasl_utils_t) -> Option<String> {
+unsafe fn trigger_simple_callback(utils: &sasl_utils_t, id: c_int) -> Option<String> {
     let getcallback = utils.getcallback?;
     let mut callback: sasl_callback_ft = None;
     let mut context: *mut c_void = ptr::null_mut();
     let err = getcallback(
         utils.conn,
-        SASL_CB_AUTHNAME as c_ulong,
+        id as c_ulong,
         &mut callback as *mut sasl_callback_ft,
         &mut context,
     );
@@ -302,7 +913,7 @@ unsafe fn trigger_auth_name_callback(utils: &sasl_utils_t) -> Option<String> {
     let cb: sasl_getsimple_t = std::mem::transmute(callback?);
     let mut result: *const c_char = ptr::null();
     let mut len: c_uint = 0;
-    let err = cb(context, SASL_CB_AUTHNAME, &mut result, &mut len);
+    let err = cb(context, id, &mut result, &mut len);
     if err != SASL_OK || result.is_null() {
         return None;
     }
@@ -333,6 +944,78 @@ unsafe fn trigger_password_callback(utils: &sasl_utils_t) -> Option<String> {
     Some(String::from_utf8_lossy(data).to_string())
 }
 
+/// The connection's auxiliary property context, or null if libsasl2 isn't
+/// loaded or the connection has none.
+unsafe fn auxprop_context(utils: &sasl_utils_t) -> *mut propctx {
+    let getctx = libc::dlsym(libc::RTLD_DEFAULT, c"sasl_auxprop_getctx".as_ptr());
+    if getctx.is_null() || utils.conn.is_null() {
+        return ptr::null_mut();
+    }
+    let getctx: sasl_auxprop_getctx_t = std::mem::transmute(getctx);
+    getctx(utils.conn)
+}
+
+/// Read a named SASL property for `mechanism`: the connection's auxiliary
+/// property of that name, else the plugin option of that name, which is
+/// how applications without auxprop support can pass it.
+unsafe fn read_property(
+    utils: &sasl_utils_t,
+    propctx: *mut propctx,
+    mechanism: Mechanism,
+    name: &str,
+) -> Option<String> {
+    read_auxprop(utils, propctx, name).or_else(|| read_option(utils, mechanism, name))
+}
+
+/// The first value of auxiliary property `name`, through `prop_get`.
+unsafe fn read_auxprop(utils: &sasl_utils_t, propctx: *mut propctx, name: &str) -> Option<String> {
+    if propctx.is_null() {
+        return None;
+    }
+    let mut val = utils.prop_get?(propctx);
+    if val.is_null() {
+        return None;
+    }
+    while !(*val).name.is_null() {
+        if CStr::from_ptr((*val).name).to_bytes() == name.as_bytes() {
+            if (*val).nvalues == 0 || (*val).values.is_null() || (*(*val).values).is_null() {
+                return None;
+            }
+            let value = CStr::from_ptr(*(*val).values).to_string_lossy();
+            return (!value.is_empty()).then(|| value.into_owned());
+        }
+        val = val.add(1);
+    }
+    None
+}
+
+/// A SASL option for the `mechanism` plugin, through `getopt`.
+unsafe fn read_option(utils: &sasl_utils_t, mechanism: Mechanism, name: &str) -> Option<String> {
+    let getopt = utils.getopt?;
+    let c_name = CString::new(name).ok()?;
+    let mut result: *const c_char = ptr::null();
+    let mut len: c_uint = 0;
+    let err = getopt(
+        utils.getopt_context,
+        mechanism.name().as_ptr(),
+        c_name.as_ptr(),
+        &mut result,
+        &mut len,
+    );
+    if err != SASL_OK || result.is_null() {
+        return None;
+    }
+    let data = if len > 0 {
+        slice::from_raw_parts(result as *const u8, len as usize)
+    } else {
+        CStr::from_ptr(result).to_bytes()
+    };
+    if data.is_empty() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(data).to_string())
+}
+
 unsafe fn request_prompts(
     params: *mut sasl_client_params_t,
     prompts: *mut *mut sasl_interact_t,
@@ -379,3 +1062,1080 @@ unsafe fn request_prompts(
     *prompts = ptr;
     SASL_INTERACT
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{fake_endpoint, test_config};
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    fn reply(client: &mut Client, from_server: &[u8]) -> Result<c_int, XoauthError> {
+        client.token_sent_step(from_server)
+    }
+
+    #[test]
+    fn test_success_status() {
+        let (mut client, _f) = client_with_token(test_config("{}"));
+        assert_eq!(reply(&mut client, br#"{"status":"200"}"#), Ok(SASL_OK));
+        assert_eq!(reply(&mut client, br#"{"status":""}"#), Ok(SASL_OK));
+        assert_eq!(reply(&mut client, b"OK"), Ok(SASL_OK));
+        assert_eq!(reply(&mut client, br#"{"status":"500"}"#), Err(XoauthError::Parse));
+
+        let config = test_config(r#"{ "success_status": ["200", "235"] }"#);
+        let (mut client, _f) = client_with_token(config);
+        assert_eq!(reply(&mut client, br#"{"status":"235"}"#), Ok(SASL_OK));
+    }
+
+    /// A `SaslEnv` answering callbacks from a list, recording what the
+    /// client asks of it.
+    #[derive(Default)]
+    struct FakeEnv {
+        callbacks: Vec<(c_int, String)>,
+        prompts_requested: Option<(bool, bool)>,
+        canonicalized: Vec<(String, c_uint)>,
+        announced: bool,
+    }
+
+    impl SaslEnv for FakeEnv {
+        fn server_fqdn(&self) -> Option<String> {
+            None
+        }
+
+        fn has_prompt_answers(&self) -> bool {
+            false
+        }
+
+        fn prompt_answer(&self, _id: c_int) -> String {
+            String::new()
+        }
+
+        fn callback(&self, id: c_int) -> Option<String> {
+            let (_, value) = self.callbacks.iter().find(|(i, _)| *i == id)?;
+            Some(value.clone())
+        }
+
+        fn property(&self, _mechanism: Mechanism, _name: &str) -> Option<String> {
+            None
+        }
+
+        fn can_prompt(&self) -> bool {
+            true
+        }
+
+        fn request_prompts(&mut self, need_auth_name: bool, need_password: bool) -> c_int {
+            self.prompts_requested = Some((need_auth_name, need_password));
+            SASL_INTERACT
+        }
+
+        fn free_prompts(&mut self) {}
+
+        fn canon_user(&mut self, user: &str, flags: c_uint) -> c_int {
+            self.canonicalized.push((user.to_string(), flags));
+            SASL_OK
+        }
+
+        fn set_no_security_layer(&mut self) {}
+
+        fn announce_version(&mut self) {
+            self.announced = true;
+        }
+    }
+
+    #[test]
+    fn test_steps_with_fake_env() {
+        let mut f = NamedTempFile::new().unwrap();
+        write!(f, r#"{{ "refresh_token": "rt", "access_token": "at", "expiry": "9999999999" }}"#)
+            .unwrap();
+
+        let mut client = Client::with_config(test_config("{}"), Mechanism::XOAuth2);
+        let mut env = FakeEnv::default();
+        assert_eq!(client.step(&mut env, b""), (SASL_INTERACT, None));
+        assert_eq!(env.prompts_requested, Some((true, true)));
+        assert_eq!(client.state, State::Initial);
+
+        let mut client = Client::with_config(test_config("{}"), Mechanism::XOAuth2);
+        let mut env = FakeEnv {
+            callbacks: vec![
+                (SASL_CB_AUTHNAME, "me@example.com".to_string()),
+                (SASL_CB_PASS, f.path().to_str().unwrap().to_string()),
+            ],
+            ..Default::default()
+        };
+        let (err, sent) = client.step(&mut env, b"");
+        assert_eq!(err, SASL_OK);
+        assert_eq!(sent, Some(&b"user=me@example.com\x01auth=Bearer at\x01\x01"[..]));
+        assert_eq!(client.state, State::TokenSent);
+        let flags = (SASL_CU_AUTHID | SASL_CU_AUTHZID) as c_uint;
+        assert_eq!(env.canonicalized, vec![("me@example.com".to_string(), flags)]);
+
+        assert_eq!(client.step(&mut env, br#"{"status":"200"}"#), (SASL_OK, None));
+        assert_eq!(client.state, State::Done);
+        assert!(env.announced);
+    }
+
+    #[test]
+    fn test_password_token_path_redacted_in_log_context() {
+        let mut f = NamedTempFile::new().unwrap();
+        write!(f, r#"{{ "refresh_token": "rt", "access_token": "at", "expiry": "9999999999" }}"#)
+            .unwrap();
+        let path = f.path().to_str().unwrap().to_string();
+        let mut client = Client::with_config(test_config("{}"), Mechanism::XOAuth2);
+        let mut env = FakeEnv {
+            callbacks: vec![
+                (SASL_CB_AUTHNAME, "me@example.com".to_string()),
+                (SASL_CB_PASS, path.clone()),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(client.step(&mut env, b"").0, SASL_OK);
+
+        let line: serde_json::Value =
+            serde_json::from_str(&client.log.json_line(LogLevel::Info, "x")).unwrap();
+        assert_eq!(line["token_path"], redact(&path));
+        assert_ne!(line["token_path"], path);
+    }
+
+    /// Run a step with `from_server` as the server's data.
+    unsafe fn step(client: &mut Client, from_server: &[u8]) -> c_int {
+        let utils = sasl_utils_t::default();
+        let mut params = sasl_client_params_t {
+            utils: &utils,
+            ..Default::default()
+        };
+        let mut out: *const c_char = ptr::null();
+        let mut out_len: c_uint = 0;
+        client.do_step(
+            &mut params,
+            from_server.as_ptr() as *const c_char,
+            from_server.len() as c_uint,
+            ptr::null_mut(),
+            &mut out,
+            &mut out_len,
+            ptr::null_mut(),
+        )
+    }
+
+    #[test]
+    fn test_confirm_blank_status() {
+        // By default a blank reply is success.
+        let (mut client, _f) = client_with_token(test_config("{}"));
+        client.state = State::TokenSent;
+        unsafe {
+            assert_eq!(step(&mut client, b""), SASL_OK);
+        }
+        assert_eq!(client.state, State::Done);
+
+        let config = test_config(r#"{ "confirm_blank_status": true }"#);
+        let (mut client, _f) = client_with_token(config);
+        client.state = State::TokenSent;
+        unsafe {
+            assert_eq!(step(&mut client, b""), SASL_CONTINUE);
+            assert_eq!(client.state, State::Confirming);
+            assert_eq!(step(&mut client, b""), SASL_OK);
+        }
+        assert_eq!(client.state, State::Done);
+
+        let (mut client, _f) = client_with_token(config);
+        client.state = State::TokenSent;
+        unsafe {
+            assert_eq!(step(&mut client, b"\r\n"), SASL_CONTINUE);
+            assert_eq!(step(&mut client, br#"{"status":"500"}"#), SASL_BADPROT);
+        }
+        assert_eq!(client.state, State::Failed);
+    }
+
+    #[test]
+    fn test_step_after_completion() {
+        let (mut client, _f) = client_with_token(test_config("{}"));
+        client.state = State::TokenSent;
+        unsafe {
+            assert_eq!(step(&mut client, br#"{"status":"200"}"#), SASL_OK);
+            assert_eq!(client.state, State::Done);
+            // Not mistaken for a rejection of the token.
+            assert_eq!(step(&mut client, br#"{"status":"401"}"#), SASL_OK);
+            assert_eq!(client.state, State::Done);
+        }
+
+        let (mut client, _f) = client_with_token(test_config("{}"));
+        client.state = State::TokenSent;
+        unsafe {
+            assert_eq!(step(&mut client, br#"{"status":"500"}"#), SASL_BADPROT);
+            assert_eq!(client.state, State::Failed);
+            assert_eq!(step(&mut client, br#"{"status":"200"}"#), SASL_FAIL);
+            assert_eq!(client.state, State::Failed);
+        }
+    }
+
+    #[test]
+    fn test_result_line() {
+        let config = test_config(r#"{ "emit_result_line": true }"#);
+        let (mut client, _f) = client_with_token(config);
+        client.state = State::TokenSent;
+        unsafe {
+            assert_eq!(step(&mut client, br#"{"status":"200"}"#), SASL_OK);
+            assert_eq!(step(&mut client, br#"{"status":"200"}"#), SASL_OK);
+        }
+        assert_eq!(
+            client.log.always_lines(),
+            vec![
+                "sasl-xoauth2: RESULT user=user@example.com mechanism=XOAUTH2 result=ok \
+                 refreshed=false"
+            ]
+        );
+
+        let (mut client, _f) = client_with_token(config);
+        client.mechanism = Mechanism::OAuthBearer;
+        client.state = State::TokenSent;
+        unsafe {
+            assert_eq!(step(&mut client, br#"{"status":"500"}"#), SASL_BADPROT);
+        }
+        assert_eq!(
+            client.log.always_lines(),
+            vec![
+                "sasl-xoauth2: RESULT user=user@example.com mechanism=OAUTHBEARER \
+                 result=fail refreshed=false error=badprot"
+            ]
+        );
+
+        // A rejected token is refreshed and the exchange retried.
+        let (url, server) = fake_endpoint(vec![(
+            200,
+            r#"{"access_token":"at-new","expires_in":3600}"#.to_string(),
+        )]);
+        let mut f = NamedTempFile::new().unwrap();
+        write!(
+            f,
+            r#"{{ "refresh_token": "rt", "access_token": "at", "expiry": "9999999999",
+                 "token_endpoint": "{}" }}"#,
+            url
+        )
+        .unwrap();
+        let mut client = Client::with_config(config, Mechanism::XOAuth2);
+        client.user = "user@example.com".to_string();
+        assert_eq!(client.load_token(f.path().to_str().unwrap()), Ok(()));
+        client.state = State::TokenSent;
+        unsafe {
+            assert_eq!(step(&mut client, br#"{"status":"401"}"#), SASL_TRYAGAIN);
+        }
+        server.join().unwrap();
+        assert_eq!(
+            client.log.always_lines(),
+            vec![
+                "sasl-xoauth2: RESULT user=user@example.com mechanism=XOAUTH2 \
+                 result=tryagain refreshed=true error=tryagain"
+            ]
+        );
+
+        // Off by default.
+        let (mut client, _f) = client_with_token(test_config("{}"));
+        client.state = State::TokenSent;
+        unsafe {
+            assert_eq!(step(&mut client, br#"{"status":"200"}"#), SASL_OK);
+        }
+        assert!(client.log.always_lines().is_empty());
+    }
+
+    #[test]
+    fn test_rejected_status_refreshes() {
+        let (url, server) = fake_endpoint(vec![(
+            200,
+            r#"{"access_token":"at-new","expires_in":3600}"#.to_string(),
+        )]);
+        let mut f = NamedTempFile::new().unwrap();
+        write!(
+            f,
+            r#"{{ "refresh_token": "rt", "access_token": "at", "expiry": "9999999999",
+                 "token_endpoint": "{}" }}"#,
+            url
+        )
+        .unwrap();
+        let mut client = Client::with_config(test_config("{}"), Mechanism::XOAuth2);
+        assert_eq!(client.load_token(f.path().to_str().unwrap()), Ok(()));
+        assert_eq!(reply(&mut client, br#"{"status":"401"}"#), Err(XoauthError::Expired));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_repeated_rejection() {
+        let (url, server) = fake_endpoint(vec![(
+            200,
+            r#"{"access_token":"at-new","expires_in":3600}"#.to_string(),
+        )]);
+        let mut f = NamedTempFile::new().unwrap();
+        write!(
+            f,
+            r#"{{ "refresh_token": "rt", "access_token": "at", "expiry": "9999999999",
+                 "token_endpoint": "{}" }}"#,
+            url
+        )
+        .unwrap();
+        let path = f.path().to_str().unwrap();
+        let config = test_config("{}");
+
+        // Each MTA retry gets a new client.
+        let mut client = Client::with_config(config, Mechanism::XOAuth2);
+        assert_eq!(client.load_token(path), Ok(()));
+        assert_eq!(reply(&mut client, br#"{"status":"401"}"#), Err(XoauthError::Expired));
+        let mut client = Client::with_config(config, Mechanism::XOAuth2);
+        assert_eq!(client.load_token(path), Ok(()));
+        assert_eq!(client.retry_count, 1);
+        assert_eq!(reply(&mut client, br#"{"status":"401"}"#), Err(XoauthError::Rejected));
+        assert_eq!(server.join().unwrap().len(), 1);
+        assert_eq!(token_retries(path), 0);
+
+        // Success resets the count.
+        set_token_retries(path, 1);
+        let mut client = Client::with_config(config, Mechanism::XOAuth2);
+        assert_eq!(client.load_token(path), Ok(()));
+        client.state = State::TokenSent;
+        unsafe {
+            assert_eq!(step(&mut client, br#"{"status":"200"}"#), SASL_OK);
+        }
+        assert_eq!(token_retries(path), 0);
+
+        let config = test_config(r#"{ "max_token_retries": 0 }"#);
+        let mut client = Client::with_config(config, Mechanism::XOAuth2);
+        assert_eq!(client.load_token(path), Ok(()));
+        client.state = State::TokenSent;
+        unsafe {
+            assert_eq!(step(&mut client, br#"{"status":"401"}"#), SASL_BADAUTH);
+        }
+    }
+
+    #[test]
+    fn test_rejected_jwt_audience_is_logged() {
+        // {"aud":"https://graph.microsoft.com","sub":"x"}
+        let jwt = "eyJhbGciOiJSUzI1NiJ9.\
+                   eyJhdWQiOiJodHRwczovL2dyYXBoLm1pY3Jvc29mdC5jb20iLCJzdWIiOiJ4In0.sig";
+        let (url, server) = fake_endpoint(vec![(
+            200,
+            r#"{"access_token":"at-new","expires_in":3600}"#.to_string(),
+        )]);
+        let mut f = NamedTempFile::new().unwrap();
+        write!(
+            f,
+            r#"{{ "refresh_token": "rt", "access_token": "{}", "expiry": "9999999999",
+                 "token_endpoint": "{}" }}"#,
+            jwt, url
+        )
+        .unwrap();
+        let mut client = Client::with_config(test_config("{}"), Mechanism::XOAuth2);
+        assert_eq!(client.load_token(f.path().to_str().unwrap()), Ok(()));
+        assert_eq!(reply(&mut client, br#"{"status":"401"}"#), Err(XoauthError::Expired));
+        server.join().unwrap();
+        assert!(client
+            .log
+            .lines()
+            .iter()
+            .any(|l| l.contains("audience was 'https://graph.microsoft.com'")));
+    }
+
+    #[test]
+    fn test_server_scope_is_logged() {
+        let config =
+            test_config(r#"{ "scope": "https://outlook.office.com/IMAP.AccessAsUser.All" }"#);
+        let (mut client, _f) = client_with_token(config);
+        client.mechanism = Mechanism::OAuthBearer;
+        let server = br#"{"status":"insufficient_scope","schemes":"bearer",
+                          "scope":"https://outlook.office.com/SMTP.Send"}"#;
+        assert_eq!(reply(&mut client, server), Err(XoauthError::Parse));
+        let lines = client.log.lines();
+        assert!(lines.iter().any(|l| l.contains("server schemes: bearer")));
+        assert!(lines
+            .iter()
+            .any(|l| l.contains("likely lacks scope 'https://outlook.office.com/SMTP.Send'")));
+
+        assert_eq!(unrequested_scopes("a b", "b a c"), Vec::<&str>::new());
+        assert_eq!(unrequested_scopes("a b", "offline_access a"), vec!["b"]);
+    }
+
+    #[test]
+    fn test_jwt_audience() {
+        // {"aud":["a","b"]}
+        assert_eq!(
+            jwt_audience("e30.eyJhdWQiOlsiYSIsImIiXX0.sig").as_deref(),
+            Some("a, b")
+        );
+        // {} has no audience
+        assert_eq!(jwt_audience("e30.e30.sig"), None);
+        assert_eq!(jwt_audience("ya29.opaque"), None);
+        assert_eq!(jwt_audience("a.!!!.c"), None);
+    }
+
+    thread_local! {
+        static CANONICALIZED: std::cell::RefCell<Vec<(String, c_uint)>> =
+            const { std::cell::RefCell::new(Vec::new()) };
+    }
+
+    unsafe extern "C" fn fake_canon_user(
+        _conn: *mut sasl_conn_t,
+        user: *const c_char,
+        len: c_uint,
+        flags: c_uint,
+        _oparams: *mut sasl_out_params_t,
+    ) -> c_int {
+        let user = slice::from_raw_parts(user as *const u8, len as usize);
+        let user = String::from_utf8_lossy(user).to_string();
+        CANONICALIZED.with(|c| c.borrow_mut().push((user, flags)));
+        SASL_OK
+    }
+
+    fn prompt(id: i32, value: &str) -> sasl_interact_t {
+        sasl_interact_t {
+            id: id as c_ulong,
+            result: value.as_ptr() as *const c_void,
+            len: value.len() as c_uint,
+            ..Default::default()
+        }
+    }
+
+    /// Run the initial step with the auth name and token path supplied as
+    /// already-answered prompts.
+    unsafe fn run_initial_step(client: &mut Client, auth_name: &str, path: &str) -> c_int {
+        run_initial_step_with_prompts(
+            client,
+            &[(SASL_CB_AUTHNAME, auth_name), (SASL_CB_PASS, path)],
+        )
+    }
+
+    unsafe fn run_initial_step_with_prompts(client: &mut Client, answers: &[(i32, &str)]) -> c_int {
+        let mut out_params = sasl_out_params_t::default();
+        run_initial_step_with_out_params(client, answers, &mut out_params)
+    }
+
+    unsafe fn run_initial_step_with_out_params(
+        client: &mut Client,
+        answers: &[(i32, &str)],
+        out_params: &mut sasl_out_params_t,
+    ) -> c_int {
+        let utils = sasl_utils_t::default();
+        let mut params = sasl_client_params_t {
+            utils: &utils,
+            canon_user: Some(fake_canon_user),
+            ..Default::default()
+        };
+        let mut prompts: Vec<sasl_interact_t> =
+            answers.iter().map(|&(id, value)| prompt(id, value)).collect();
+        prompts.push(prompt(SASL_CB_LIST_END, ""));
+        let mut prompt_need = prompts.as_mut_ptr();
+        let mut out: *const c_char = ptr::null();
+        let mut out_len: c_uint = 0;
+        client.do_step(
+            &mut params,
+            ptr::null(),
+            0,
+            &mut prompt_need,
+            &mut out,
+            &mut out_len,
+            out_params,
+        )
+    }
+
+    #[test]
+    fn test_partially_answered_prompts() {
+        let mut client = Client::with_config(test_config("{}"), Mechanism::XOAuth2);
+        let err = unsafe {
+            run_initial_step_with_prompts(
+                &mut client,
+                &[(SASL_CB_AUTHNAME, "me@example.com"), (SASL_CB_PASS, "")],
+            )
+        };
+        assert_eq!(err, SASL_BADPARAM);
+        assert_eq!(client.state, State::Failed);
+    }
+
+    #[test]
+    fn test_token_path_property_not_set() {
+        let config = test_config(r#"{ "token_path_source": "property:missing" }"#);
+        let mut client = Client::with_config(config, Mechanism::XOAuth2);
+        let utils = sasl_utils_t {
+            getopt: Some(fake_getopt),
+            ..Default::default()
+        };
+        let mut params = sasl_client_params_t {
+            utils: &utils,
+            canon_user: Some(fake_canon_user),
+            ..Default::default()
+        };
+        // Only the auth name is asked for, not a password.
+        let mut prompts = [
+            prompt(SASL_CB_AUTHNAME, "me@example.com"),
+            prompt(SASL_CB_LIST_END, ""),
+        ];
+        let mut prompt_need = prompts.as_mut_ptr();
+        let mut out: *const c_char = ptr::null();
+        let mut out_len: c_uint = 0;
+        let err = unsafe {
+            client.do_step(
+                &mut params,
+                ptr::null(),
+                0,
+                &mut prompt_need,
+                &mut out,
+                &mut out_len,
+                ptr::null_mut(),
+            )
+        };
+        assert_eq!(err, SASL_BADPARAM);
+        assert_eq!(client.state, State::Failed);
+    }
+
+    #[test]
+    fn test_no_security_layer() {
+        let mut f = NamedTempFile::new().unwrap();
+        write!(f, r#"{{ "refresh_token": "rt", "access_token": "at", "expiry": "9999999999" }}"#)
+            .unwrap();
+
+        unsafe extern "C" fn fake_decode(
+            _: *mut c_void,
+            _: *const c_char,
+            _: c_uint,
+            _: *mut *const c_char,
+            _: *mut c_uint,
+        ) -> c_int {
+            SASL_OK
+        }
+        let mut context = 0u8;
+        let mut out_params = sasl_out_params_t {
+            mech_ssf: 56,
+            maxoutbuf: 4096,
+            decode_context: &mut context as *mut u8 as *mut c_void,
+            decode: Some(fake_decode),
+            ..Default::default()
+        };
+        let mut client = Client::with_config(test_config("{}"), Mechanism::XOAuth2);
+        let err = unsafe {
+            run_initial_step_with_out_params(
+                &mut client,
+                &[(SASL_CB_AUTHNAME, "me@example.com"), (SASL_CB_PASS, f.path().to_str().unwrap())],
+                &mut out_params,
+            )
+        };
+        assert_eq!(err, SASL_OK);
+        assert_eq!(out_params.mech_ssf, 0);
+        assert_eq!(out_params.maxoutbuf, 0);
+        assert!(out_params.encode.is_none() && out_params.encode_context.is_null());
+        assert!(out_params.decode.is_none() && out_params.decode_context.is_null());
+        CANONICALIZED.with(|c| c.borrow_mut().clear());
+    }
+
+    #[test]
+    fn test_full_trace_has_no_secrets() {
+        let config = test_config(
+            r#"{ "log_full_trace_on_failure": true, "client_secret": "cs-secret-value" }"#,
+        );
+        let (url, server) = fake_endpoint(vec![(
+            200,
+            r#"{"access_token":"at-new-secret","refresh_token":"rt-new-secret","expires_in":3600}"#
+                .to_string(),
+        )]);
+        let mut f = NamedTempFile::new().unwrap();
+        write!(
+            f,
+            r#"{{ "refresh_token": "rt-old-secret", "access_token": "at-old-secret",
+                 "expiry": "0", "token_endpoint": "{}" }}"#,
+            url
+        )
+        .unwrap();
+        let path = f.path().to_str().unwrap();
+
+        let mut client = Client::with_config(config, Mechanism::XOAuth2);
+        let err = unsafe { run_initial_step(&mut client, "me@example.com", path) };
+        assert_eq!(err, SASL_OK);
+        server.join().unwrap();
+        assert!(client.token.as_ref().unwrap().refreshed());
+
+        let lines = client.log.lines();
+        assert!(lines.iter().any(|l| l.contains("password/path='")));
+        let secrets = [
+            "cs-secret-value",
+            "at-old-secret",
+            "at-new-secret",
+            "rt-old-secret",
+            "rt-new-secret",
+        ];
+        for secret in secrets {
+            assert!(lines.iter().all(|l| !l.contains(secret)), "{} leaked", secret);
+        }
+    }
+
+    #[test]
+    fn test_user_override_is_recanonicalized() {
+        let mut f = NamedTempFile::new().unwrap();
+        write!(
+            f,
+            r#"{{ "refresh_token": "rt", "access_token": "at", "expiry": "9999999999",
+                 "user": "shared@example.com" }}"#
+        )
+        .unwrap();
+
+        let mut client = Client::with_config(test_config("{}"), Mechanism::XOAuth2);
+        let err = unsafe { run_initial_step(&mut client, "me@example.com", f.path().to_str().unwrap()) };
+        assert_eq!(err, SASL_OK);
+        assert_eq!(client.user, "shared@example.com");
+        let both = (SASL_CU_AUTHID | SASL_CU_AUTHZID) as c_uint;
+        CANONICALIZED.with(|c| {
+            assert_eq!(
+                *c.borrow(),
+                vec![
+                    ("me@example.com".to_string(), both),
+                    ("shared@example.com".to_string(), both)
+                ]
+            );
+            c.borrow_mut().clear();
+        });
+
+        let config = test_config(r#"{ "prefer_sasl_authname": true }"#);
+        let mut client = Client::with_config(config, Mechanism::XOAuth2);
+        let err = unsafe { run_initial_step(&mut client, "me@example.com", f.path().to_str().unwrap()) };
+        assert_eq!(err, SASL_OK);
+        assert_eq!(client.user, "me@example.com");
+        assert!(client.response.starts_with(b"user=me@example.com\x01"));
+        CANONICALIZED.with(|c| c.borrow_mut().clear());
+
+        let config = test_config(r#"{ "recanonicalize_user": false }"#);
+        let mut client = Client::with_config(config, Mechanism::XOAuth2);
+        let err = unsafe { run_initial_step(&mut client, "me@example.com", f.path().to_str().unwrap()) };
+        assert_eq!(err, SASL_OK);
+        CANONICALIZED.with(|c| {
+            assert_eq!(*c.borrow(), vec![("me@example.com".to_string(), both)]);
+            c.borrow_mut().clear();
+        });
+    }
+
+    #[test]
+    fn test_authz_id_from_user_prompt() {
+        let mut f = NamedTempFile::new().unwrap();
+        write!(f, r#"{{ "refresh_token": "rt", "access_token": "at", "expiry": "9999999999" }}"#)
+            .unwrap();
+        let path = f.path().to_str().unwrap();
+
+        let mut client = Client::with_config(test_config("{}"), Mechanism::XOAuth2);
+        let err = unsafe {
+            run_initial_step_with_prompts(
+                &mut client,
+                &[
+                    (SASL_CB_AUTHNAME, "me@example.com"),
+                    (SASL_CB_USER, "shared@example.com"),
+                    (SASL_CB_PASS, path),
+                ],
+            )
+        };
+        assert_eq!(err, SASL_OK);
+        assert_eq!(client.user, "shared@example.com");
+        assert!(client.response.starts_with(b"user=shared@example.com\x01"));
+        CANONICALIZED.with(|c| {
+            assert_eq!(
+                *c.borrow(),
+                vec![
+                    ("me@example.com".to_string(), SASL_CU_AUTHID as c_uint),
+                    ("shared@example.com".to_string(), SASL_CU_AUTHZID as c_uint)
+                ]
+            );
+            c.borrow_mut().clear();
+        });
+
+        // An authz id equal to the auth name is canonicalized once.
+        let mut client = Client::with_config(test_config("{}"), Mechanism::XOAuth2);
+        let err = unsafe {
+            run_initial_step_with_prompts(
+                &mut client,
+                &[
+                    (SASL_CB_AUTHNAME, "me@example.com"),
+                    (SASL_CB_USER, "me@example.com"),
+                    (SASL_CB_PASS, path),
+                ],
+            )
+        };
+        assert_eq!(err, SASL_OK);
+        CANONICALIZED.with(|c| {
+            assert_eq!(
+                *c.borrow(),
+                vec![("me@example.com".to_string(), (SASL_CU_AUTHID | SASL_CU_AUTHZID) as c_uint)]
+            );
+            c.borrow_mut().clear();
+        });
+    }
+
+    #[test]
+    fn test_enforce_user_match() {
+        let mut f = NamedTempFile::new().unwrap();
+        write!(
+            f,
+            r#"{{ "refresh_token": "rt", "access_token": "at", "expiry": "9999999999",
+                 "user": "User@Example.com" }}"#
+        )
+        .unwrap();
+        let path = f.path().to_str().unwrap();
+
+        let load = |config: &'static Config, user: &str| {
+            let mut client = Client::with_config(config, Mechanism::XOAuth2);
+            client.user = user.to_string();
+            client.load_token(path)
+        };
+
+        let config = test_config(r#"{ "enforce_user_match": true }"#);
+        assert_eq!(load(config, "user@example.com"), Ok(()));
+        assert_eq!(load(config, "other@example.com"), Err(XoauthError::Config));
+
+        let config = test_config(r#"{ "enforce_user_match": true, "user_match": "exact" }"#);
+        assert_eq!(load(config, "user@example.com"), Err(XoauthError::Config));
+        assert_eq!(load(config, "User@Example.com"), Ok(()));
+
+        // Without enforcement the token file's user is simply adopted.
+        assert_eq!(load(test_config("{}"), "other@example.com"), Ok(()));
+    }
+
+    #[test]
+    fn test_read_only_access_token_mode() {
+        let mut f = NamedTempFile::new().unwrap();
+        // Expired, and with an unreachable endpoint: any refresh would fail.
+        let contents = r#"{ "access_token": "at-minted", "refresh_token": "rt", "expiry": "1",
+                            "token_endpoint": "http://127.0.0.1:1/token" }"#;
+        write!(f, "{}", contents).unwrap();
+
+        let config = test_config(r#"{ "mode": "read_only_access_token" }"#);
+        let mut client = Client::with_config(config, Mechanism::XOAuth2);
+        client.user = "user@example.com".to_string();
+        assert_eq!(client.load_token(f.path().to_str().unwrap()), Ok(()));
+        assert!(client.token.is_none());
+
+        let (err, bytes) = sent_bytes(&mut client);
+        assert_eq!(err, Ok(()));
+        assert_eq!(bytes, b"user=user@example.com\x01auth=Bearer at-minted\x01\x01");
+
+        // A rejection can't trigger a refresh; the exchange just fails.
+        let err = reply(&mut client, br#"{"status":"401"}"#);
+        assert_eq!(err, Err(XoauthError::Parse));
+        assert_eq!(std::fs::read_to_string(f.path()).unwrap(), contents);
+    }
+
+    unsafe extern "C" fn fake_getopt(
+        _context: *mut c_void,
+        plugin_name: *const c_char,
+        option: *const c_char,
+        result: *mut *const c_char,
+        len: *mut c_uint,
+    ) -> c_int {
+        if CStr::from_ptr(plugin_name).to_bytes() != b"XOAUTH2" {
+            return SASL_FAIL;
+        }
+        match CStr::from_ptr(option).to_bytes() {
+            b"token_path" => {
+                *result = c"/etc/tokens/user@example.com".as_ptr();
+                *len = 0;
+                SASL_OK
+            }
+            b"empty" => {
+                *result = c"".as_ptr();
+                *len = 0;
+                SASL_OK
+            }
+            _ => SASL_FAIL,
+        }
+    }
+
+    /// A client holding a token store with a valid, unexpired access token.
+    fn client_with_token(config: &'static Config) -> (Client, NamedTempFile) {
+        let mut f = NamedTempFile::new().unwrap();
+        write!(
+            f,
+            r#"{{ "refresh_token": "rt", "access_token": "ya29.token", "expiry": "9999999999" }}"#
+        )
+        .unwrap();
+        let mut client = Client::with_config(config, Mechanism::XOAuth2);
+        client.user = "user@example.com".to_string();
+        client.token = TokenStore::with_config(&client.log, f.path().to_str().unwrap(), config);
+        (client, f)
+    }
+
+    fn sent_bytes(client: &mut Client) -> (Result<(), XoauthError>, Vec<u8>) {
+        match client.send_token() {
+            Ok(()) => (Ok(()), client.response.clone()),
+            Err(e) => (Err(e), Vec::new()),
+        }
+    }
+
+    #[test]
+    fn test_send_token_raw() {
+        let (mut client, _f) = client_with_token(test_config("{}"));
+        let (err, bytes) = sent_bytes(&mut client);
+        assert_eq!(err, Ok(()));
+        assert_eq!(bytes, b"user=user@example.com\x01auth=Bearer ya29.token\x01\x01");
+
+        let mut client = Client::with_config(test_config("{}"), Mechanism::XOAuth2);
+        assert_eq!(sent_bytes(&mut client), (Err(XoauthError::NoToken), Vec::new()));
+        assert_eq!(XoauthError::NoToken.to_sasl_code(), SASL_FAIL);
+    }
+
+    #[test]
+    fn test_send_token_rejects_framing_chars() {
+        let (mut client, _f) = client_with_token(test_config("{}"));
+        client.user = "user@example.com\x01auth=Bearer x".to_string();
+        assert_eq!(sent_bytes(&mut client).0, Err(XoauthError::InvalidField("user")));
+
+        let mut f = NamedTempFile::new().unwrap();
+        write!(
+            f,
+            r#"{{ "refresh_token": "rt", "access_token": "ya29.to\r\nken", "expiry": "9999999999" }}"#
+        )
+        .unwrap();
+        let config = test_config("{}");
+        let mut client = Client::with_config(config, Mechanism::XOAuth2);
+        client.user = "user@example.com".to_string();
+        client.token = TokenStore::with_config(&client.log, f.path().to_str().unwrap(), config);
+        assert_eq!(
+            sent_bytes(&mut client).0,
+            Err(XoauthError::InvalidField("access token"))
+        );
+
+        client.fixed_token = Some("ya29\0token".to_string());
+        client.token = None;
+        assert_eq!(
+            sent_bytes(&mut client).0,
+            Err(XoauthError::InvalidField("access token"))
+        );
+    }
+
+    #[test]
+    fn test_send_token_base64() {
+        let config = test_config(r#"{ "base64_initial_response": true }"#);
+        let (mut client, _f) = client_with_token(config);
+        let (err, bytes) = sent_bytes(&mut client);
+        assert_eq!(err, Ok(()));
+        assert_eq!(
+            bytes,
+            b"dXNlcj11c2VyQGV4YW1wbGUuY29tAWF1dGg9QmVhcmVyIHlhMjkudG9rZW4BAQ=="
+        );
+    }
+
+    #[test]
+    fn test_send_token_oauthbearer() {
+        let (mut client, _f) = client_with_token(test_config("{}"));
+        client.mechanism = Mechanism::OAuthBearer;
+        let (err, bytes) = sent_bytes(&mut client);
+        assert_eq!(err, Ok(()));
+        assert_eq!(
+            bytes,
+            b"n,a=user@example.com,\x01auth=Bearer ya29.token\x01\x01"
+        );
+    }
+
+    #[test]
+    fn test_token_rejected_status() {
+        assert!(Mechanism::XOAuth2.is_token_rejected("401"));
+        assert!(!Mechanism::XOAuth2.is_token_rejected("invalid_token"));
+        assert!(Mechanism::OAuthBearer.is_token_rejected("invalid_token"));
+        assert!(Mechanism::OAuthBearer.is_token_rejected("400"));
+        assert!(!Mechanism::OAuthBearer.is_token_rejected("invalid_request"));
+    }
+
+    thread_local! {
+        /// Auxiliary properties of the fake context: requested names and
+        /// their value, if set.
+        static PROPS: std::cell::RefCell<BTreeMap<String, Option<String>>> =
+            const { std::cell::RefCell::new(BTreeMap::new()) };
+    }
+
+    /// A context for the fake prop functions, which never dereference it.
+    fn fake_propctx() -> *mut propctx {
+        ptr::NonNull::dangling().as_ptr()
+    }
+
+    unsafe extern "C" fn fake_prop_get(_ctx: *mut propctx) -> *const propval {
+        // Leaked, as SASL keeps them for the context's lifetime.
+        let leak = |s: &str| CString::new(s).unwrap().into_raw() as *const c_char;
+        let mut vals: Vec<propval> = PROPS.with(|p| {
+            p.borrow()
+                .iter()
+                .map(|(name, value)| {
+                    let values: Vec<*const c_char> = match value {
+                        Some(v) => vec![leak(v), ptr::null()],
+                        None => vec![ptr::null()],
+                    };
+                    propval {
+                        name: leak(name),
+                        nvalues: value.is_some() as c_uint,
+                        values: Box::leak(values.into_boxed_slice()).as_mut_ptr(),
+                        valsize: 0,
+                    }
+                })
+                .collect()
+        });
+        vals.push(propval { name: ptr::null(), values: ptr::null_mut(), nvalues: 0, valsize: 0 });
+        Box::leak(vals.into_boxed_slice()).as_ptr()
+    }
+
+    fn set_props(props: &[(&str, Option<&str>)]) {
+        PROPS.with(|p| {
+            *p.borrow_mut() = props
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.map(str::to_string)))
+                .collect()
+        });
+    }
+
+    unsafe extern "C" fn fake_prop_request(
+        _ctx: *mut propctx,
+        mut names: *mut *const c_char,
+    ) -> c_int {
+        while !(*names).is_null() {
+            let name = CStr::from_ptr(*names).to_string_lossy().into_owned();
+            PROPS.with(|p| {
+                p.borrow_mut().entry(name).or_default();
+            });
+            names = names.add(1);
+        }
+        SASL_OK
+    }
+
+    unsafe extern "C" fn fake_prop_set(
+        _ctx: *mut propctx,
+        name: *const c_char,
+        value: *const c_char,
+        vallen: c_int,
+    ) -> c_int {
+        let name = CStr::from_ptr(name).to_string_lossy().into_owned();
+        let value = slice::from_raw_parts(value as *const u8, vallen as usize);
+        let value = String::from_utf8_lossy(value).into_owned();
+        PROPS.with(|p| match p.borrow_mut().get_mut(&name) {
+            // Like SASL, only requested properties can be set.
+            Some(slot) => {
+                *slot = Some(value);
+                SASL_OK
+            }
+            None => SASL_BADPARAM,
+        })
+    }
+
+    #[test]
+    fn test_read_property() {
+        let utils = sasl_utils_t {
+            getopt: Some(fake_getopt),
+            prop_get: Some(fake_prop_get),
+            ..Default::default()
+        };
+        let null = ptr::null_mut();
+        unsafe {
+            assert_eq!(
+                read_property(&utils, null, Mechanism::XOAuth2, "token_path").as_deref(),
+                Some("/etc/tokens/user@example.com")
+            );
+            assert_eq!(read_property(&utils, null, Mechanism::XOAuth2, "empty"), None);
+            assert_eq!(read_property(&utils, null, Mechanism::XOAuth2, "missing"), None);
+            assert_eq!(read_property(&utils, null, Mechanism::OAuthBearer, "token_path"), None);
+
+            // The connection's auxiliary property comes first.
+            set_props(&[
+                ("token_path", Some("/etc/tokens/from-auxprop.json")),
+                ("requested", None),
+                ("other", Some("x")),
+            ]);
+            let ctx = fake_propctx();
+            assert_eq!(
+                read_property(&utils, ctx, Mechanism::OAuthBearer, "token_path").as_deref(),
+                Some("/etc/tokens/from-auxprop.json")
+            );
+            assert_eq!(read_property(&utils, ctx, Mechanism::OAuthBearer, "requested"), None);
+            assert_eq!(read_property(&utils, ctx, Mechanism::OAuthBearer, "missing"), None);
+            set_props(&[("other", Some("x"))]);
+            assert_eq!(
+                read_property(&utils, ctx, Mechanism::XOAuth2, "token_path").as_deref(),
+                Some("/etc/tokens/user@example.com")
+            );
+        }
+    }
+
+    #[test]
+    fn test_read_property_without_getopt() {
+        let utils = sasl_utils_t::default();
+        unsafe {
+            let null = ptr::null_mut();
+            assert_eq!(read_property(&utils, null, Mechanism::XOAuth2, "token_path"), None);
+            // No prop_get: the context can't be read.
+            let ctx = fake_propctx();
+            assert_eq!(read_property(&utils, ctx, Mechanism::XOAuth2, "token_path"), None);
+            assert!(auxprop_context(&utils).is_null());
+        }
+    }
+
+    #[test]
+    fn test_token_path_from_auxprop() {
+        let mut f = NamedTempFile::new().unwrap();
+        write!(f, r#"{{ "refresh_token": "rt", "access_token": "at", "expiry": "9999999999" }}"#)
+            .unwrap();
+        set_props(&[("xoauth2_token_path", Some(f.path().to_str().unwrap()))]);
+        let config = test_config(r#"{ "token_path_source": "property:xoauth2_token_path" }"#);
+        let mut client = Client::with_config(config, Mechanism::XOAuth2);
+        let utils = sasl_utils_t {
+            prop_get: Some(fake_prop_get),
+            ..Default::default()
+        };
+        let mut params = sasl_client_params_t {
+            utils: &utils,
+            canon_user: Some(fake_canon_user),
+            ..Default::default()
+        };
+        let mut prompts = [
+            prompt(SASL_CB_AUTHNAME, "me@example.com"),
+            prompt(SASL_CB_LIST_END, ""),
+        ];
+        let mut prompt_need = prompts.as_mut_ptr();
+        let mut env = unsafe { FfiEnv::new(&mut params, &mut prompt_need, ptr::null_mut()) };
+        env.propctx = fake_propctx();
+        let (err, sent) = client.step(&mut env, b"");
+        assert_eq!(err, SASL_OK);
+        assert_eq!(sent, Some(&b"user=me@example.com\x01auth=Bearer at\x01\x01"[..]));
+    }
+
+    #[test]
+    fn test_version_property_set_on_success() {
+        set_props(&[]);
+        let (mut client, _f) = client_with_token(test_config("{}"));
+        client.state = State::TokenSent;
+        let utils = sasl_utils_t {
+            prop_request: Some(fake_prop_request),
+            prop_set: Some(fake_prop_set),
+            ..Default::default()
+        };
+        let mut params = sasl_client_params_t {
+            utils: &utils,
+            ..Default::default()
+        };
+        let mut env = unsafe { FfiEnv::new(&mut params, ptr::null_mut(), ptr::null_mut()) };
+        env.propctx = fake_propctx();
+        assert_eq!(client.step(&mut env, b"").0, SASL_OK);
+
+        let version = PROPS.with(|p| p.borrow().get("xoauth2_plugin_version").cloned());
+        assert_eq!(version, Some(Some(env!("CARGO_PKG_VERSION").to_string())));
+    }
+
+    #[test]
+    fn test_version_not_set_without_prop_api() {
+        set_props(&[]);
+        // No property context, or no prop functions: auth still succeeds.
+        for (propctx, utils) in [
+            (
+                ptr::null_mut(),
+                sasl_utils_t {
+                    prop_request: Some(fake_prop_request),
+                    prop_set: Some(fake_prop_set),
+                    ..Default::default()
+                },
+            ),
+            (fake_propctx(), sasl_utils_t::default()),
+        ] {
+            let (mut client, _f) = client_with_token(test_config("{}"));
+            client.state = State::TokenSent;
+            let mut params = sasl_client_params_t {
+                utils: &utils,
+                ..Default::default()
+            };
+            let mut env = unsafe { FfiEnv::new(&mut params, ptr::null_mut(), ptr::null_mut()) };
+            env.propctx = propctx;
+            assert_eq!(client.step(&mut env, b"").0, SASL_OK);
+        }
+        assert!(PROPS.with(|p| p.borrow().is_empty()));
+    }
+}
@@ -0,0 +1,176 @@
+//! Token file encryption with AES-256-GCM, in builds with the `encryption`
+//! feature.
+//!
+//! An encrypted token file is `MAGIC`, a random 96-bit nonce, then the
+//! token JSON sealed with the key from `token_encryption_key_file`. Files
+//! without `MAGIC` are read as plaintext, so existing token files are
+//! encrypted the next time they're written.
+
+use std::fmt;
+
+use crate::config::Config;
+
+/// Start of every encrypted token file.
+const MAGIC: &[u8] = b"sasl-xoauth2-aes256gcm\n";
+
+/// Why encryption fails in builds without the `encryption` feature.
+pub(crate) const NOT_BUILT: &str =
+    "token file encryption needs a build with the `encryption` feature";
+
+/// A token file encryption key, from 64 hex digits.
+#[derive(Clone, PartialEq)]
+pub struct Key([u8; 32]);
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Key(..)")
+    }
+}
+
+impl Key {
+    /// Parse a key file's contents, as written by `openssl rand -hex 32`.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let text = text.trim();
+        let mut key = [0u8; 32];
+        if text.len() != key.len() * 2 || !text.is_ascii() {
+            return Err("the key must be 64 hex digits".to_string());
+        }
+        for (byte, hex) in key.iter_mut().zip(text.as_bytes().chunks(2)) {
+            let hex = std::str::from_utf8(hex).map_err(|e| e.to_string())?;
+            *byte = u8::from_str_radix(hex, 16)
+                .map_err(|_| "the key must be 64 hex digits".to_string())?;
+        }
+        Ok(Key(key))
+    }
+}
+
+/// Whether token file contents as read are encrypted.
+pub fn is_encrypted(contents: &[u8]) -> bool {
+    contents.starts_with(MAGIC)
+}
+
+/// Token file contents as read, decrypted if encrypted.
+pub fn open(config: &Config, contents: Vec<u8>) -> Result<Vec<u8>, String> {
+    let Some(sealed) = contents.strip_prefix(MAGIC) else {
+        return Ok(contents);
+    };
+    match config.token_encryption_key {
+        Some(ref key) => decrypt(key, sealed),
+        None => Err("the token file is encrypted but token_encryption_key_file is not set".into()),
+    }
+}
+
+/// Token file contents to write: encrypted if a key is configured.
+/// Fails rather than give plaintext if `require_encryption` is set, or the
+/// file was `encrypted` when read.
+pub fn seal(config: &Config, contents: Vec<u8>, encrypted: bool) -> Result<Vec<u8>, String> {
+    match config.token_encryption_key {
+        Some(ref key) => encrypt(key, contents),
+        None if config.require_encryption => Err(
+            "require_encryption is set but token_encryption_key_file is not, refusing to \
+             write plaintext"
+                .to_string(),
+        ),
+        None if encrypted => Err(
+            "the token file was encrypted but token_encryption_key_file is not set, refusing \
+             to write plaintext"
+                .to_string(),
+        ),
+        None => Ok(contents),
+    }
+}
+
+#[cfg(feature = "encryption")]
+fn encrypt(key: &Key, mut contents: Vec<u8>) -> Result<Vec<u8>, String> {
+    use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+    use ring::rand::{SecureRandom, SystemRandom};
+
+    let key = UnboundKey::new(&AES_256_GCM, &key.0).map_err(|_| "invalid key")?;
+    let mut nonce = [0u8; NONCE_LEN];
+    SystemRandom::new()
+        .fill(&mut nonce)
+        .map_err(|_| "no randomness for the nonce")?;
+    LessSafeKey::new(key)
+        .seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(MAGIC),
+            &mut contents,
+        )
+        .map_err(|_| "encryption failed")?;
+    Ok([MAGIC, &nonce, &contents].concat())
+}
+
+#[cfg(feature = "encryption")]
+fn decrypt(key: &Key, sealed: &[u8]) -> Result<Vec<u8>, String> {
+    use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+
+    let key = UnboundKey::new(&AES_256_GCM, &key.0).map_err(|_| "invalid key")?;
+    if sealed.len() < NONCE_LEN {
+        return Err("the encrypted token file is truncated".to_string());
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    let nonce = Nonce::try_assume_unique_for_key(nonce).map_err(|_| "invalid nonce")?;
+    let mut contents = ciphertext.to_vec();
+    let len = LessSafeKey::new(key)
+        .open_in_place(nonce, Aad::from(MAGIC), &mut contents)
+        .map_err(|_| "decryption failed: wrong key, or the token file is corrupted")?
+        .len();
+    contents.truncate(len);
+    Ok(contents)
+}
+
+#[cfg(not(feature = "encryption"))]
+fn encrypt(_key: &Key, _contents: Vec<u8>) -> Result<Vec<u8>, String> {
+    Err(NOT_BUILT.to_string())
+}
+
+#[cfg(not(feature = "encryption"))]
+fn decrypt(_key: &Key, _sealed: &[u8]) -> Result<Vec<u8>, String> {
+    Err(NOT_BUILT.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::test_config;
+
+    #[test]
+    fn test_parse_key() {
+        let hex = "00112233445566778899aabbccddeeff00112233445566778899AABBCCDDEEFF\n";
+        let key = Key::parse(hex).unwrap();
+        assert_eq!(key.0[1], 0x11);
+        assert_eq!(key.0[31], 0xff);
+        assert_eq!(format!("{:?}", key), "Key(..)");
+        assert!(Key::parse("0011").is_err());
+        assert!(Key::parse(&hex.replace('0', "g")).is_err());
+    }
+
+    #[test]
+    fn test_plaintext() {
+        let config = test_config("{}");
+        assert_eq!(open(config, b"{}".to_vec()), Ok(b"{}".to_vec()));
+        assert_eq!(seal(config, b"{}".to_vec(), false), Ok(b"{}".to_vec()));
+
+        let encrypted = [MAGIC, b"sealed"].concat();
+        assert!(is_encrypted(&encrypted));
+        assert!(!is_encrypted(b"{}"));
+        assert!(open(config, encrypted).unwrap_err().contains("token_encryption_key_file"));
+        assert!(seal(config, b"{}".to_vec(), true).is_err());
+        let config = test_config(r#"{ "require_encryption": true }"#);
+        assert!(seal(config, b"{}".to_vec(), false).is_err());
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_round_trip() {
+        let key = Key::parse(&"42".repeat(32)).unwrap();
+        let sealed = encrypt(&key, b"{\"refresh_token\":\"rt\"}".to_vec()).unwrap();
+        assert!(sealed.starts_with(MAGIC));
+        let plain = decrypt(&key, &sealed[MAGIC.len()..]).unwrap();
+        assert_eq!(plain, b"{\"refresh_token\":\"rt\"}");
+
+        let other = Key::parse(&"43".repeat(32)).unwrap();
+        assert!(decrypt(&other, &sealed[MAGIC.len()..]).is_err());
+        assert!(decrypt(&key, b"short").is_err());
+    }
+}
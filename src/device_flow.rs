@@ -0,0 +1,303 @@
+//! OAuth2 device authorization grant (RFC 8628), for `sasl-xoauth2-test
+//! --device-code` to get a fresh token file once the refresh token is
+//! expired or revoked. Not used by the plugin itself.
+
+use serde::Deserialize;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::config::{ClientAuthMethod, Config};
+use crate::crypto;
+use crate::token_store::{
+    basic_auth, expand_tenant, is_tls_error, read_body, read_token_file, sync_parent_dir,
+};
+
+const DEVICE_CODE_GRANT_TYPE: &str = "urn:ietf:params:oauth:grant-type:device_code";
+
+/// Poll interval when the server doesn't give one (RFC 8628 §3.2).
+const DEFAULT_INTERVAL_SECS: u64 = 5;
+
+/// What the user needs to authorize the device.
+#[derive(Debug, Deserialize)]
+pub struct DeviceCode {
+    #[serde(default)]
+    device_code: String,
+    pub user_code: String,
+    /// Google calls this `verification_url`.
+    #[serde(alias = "verification_url")]
+    pub verification_uri: String,
+    /// Provider's own instructions, if any.
+    #[serde(default)]
+    pub message: Option<String>,
+    expires_in: u64,
+    #[serde(default)]
+    interval: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    #[serde(default)]
+    access_token: Option<String>,
+    #[serde(default)]
+    refresh_token: Option<String>,
+    #[serde(default)]
+    expires_in: Option<i64>,
+    #[serde(default)]
+    error: Option<String>,
+    #[serde(default)]
+    error_description: Option<String>,
+}
+
+/// POST `form` to `url`, returning the parsed JSON body whatever the status.
+fn post_form<T: serde::de::DeserializeOwned>(
+    config: &Config,
+    url: &str,
+    form: &[(&str, &str)],
+) -> Result<T, String> {
+    let mut request = config
+        .agent()?
+        .post(url)
+        .header("User-Agent", "sasl-xoauth2-rs device flow");
+    if config.client_auth_method == ClientAuthMethod::Basic {
+        request = request.header(
+            "Authorization",
+            basic_auth(&config.client_id, &config.client_secret),
+        );
+    }
+    let response = request
+        .send_form(form.iter().copied())
+        .map_err(|e| match is_tls_error(&e) {
+            true => format!("TLS error from {}: {}", url, e),
+            false => format!("HTTP error from {}: {}", url, e),
+        })?;
+    let status = response.status();
+    let body = read_body(response).map_err(|e| format!("reading response from {}: {}", url, e))?;
+    serde_json::from_str(&body)
+        .map_err(|e| format!("unexpected HTTP {} response from {}: {}", status.as_u16(), url, e))
+}
+
+/// Client credentials for the device flow requests, unless they go in
+/// an `Authorization` header.
+fn client_form<'a>(config: &'a Config, form: &mut Vec<(&'a str, &'a str)>) {
+    if config.client_auth_method == ClientAuthMethod::Basic {
+        return;
+    }
+    form.push(("client_id", &config.client_id));
+    if !config.client_secret.is_empty() {
+        form.push(("client_secret", &config.client_secret));
+    }
+}
+
+/// Start the flow at `device_authorization_endpoint`.
+pub fn request_device_code(config: &Config) -> Result<DeviceCode, String> {
+    if config.device_authorization_endpoint.is_empty() {
+        return Err("device_authorization_endpoint is not set in the config".to_string());
+    }
+    let mut form = Vec::new();
+    client_form(config, &mut form);
+    if !config.scope().is_empty() {
+        form.push(("scope", config.scope()));
+    }
+    let code: DeviceCode = post_form(config, &config.device_authorization_endpoint, &form)?;
+    if code.device_code.is_empty() {
+        return Err("device authorization response has no device_code".to_string());
+    }
+    Ok(code)
+}
+
+/// Poll the token endpoint until the user authorizes the device, then
+/// write the tokens to `token_path`.
+pub fn poll_and_write(config: &Config, code: &DeviceCode, token_path: &str) -> Result<(), String> {
+    let token_endpoint = config
+        .token_endpoint()
+        .as_slice()
+        .first()
+        .ok_or("token_endpoint is not set in the config")?;
+    let token_endpoint = &expand_tenant(token_endpoint, tenant_of(config, token_path).as_deref());
+    let deadline = Instant::now() + Duration::from_secs(code.expires_in);
+    let mut interval = Duration::from_secs(code.interval.unwrap_or(DEFAULT_INTERVAL_SECS));
+
+    let mut form = vec![
+        ("grant_type", DEVICE_CODE_GRANT_TYPE),
+        ("device_code", code.device_code.as_str()),
+    ];
+    client_form(config, &mut form);
+
+    loop {
+        if Instant::now() >= deadline {
+            return Err("the device code expired before it was authorized".to_string());
+        }
+        std::thread::sleep(interval);
+        let resp: TokenResponse = post_form(config, token_endpoint, &form)?;
+        match resp.error.as_deref() {
+            None => return write_token_file(config, token_path, resp),
+            Some("authorization_pending") => {}
+            Some("slow_down") => interval += Duration::from_secs(5),
+            Some(error) => {
+                return Err(match resp.error_description {
+                    Some(description) => format!("{}: {}", error, description),
+                    None => error.to_string(),
+                })
+            }
+        }
+    }
+}
+
+/// The `tenant` of an existing token file at `token_path`.
+fn tenant_of(config: &Config, token_path: &str) -> Option<String> {
+    let (contents, _) = read_token_file(token_path, config).ok()?;
+    let token: serde_json::Value = serde_json::from_slice(&contents).ok()?;
+    token.get("tenant")?.as_str().map(str::to_string)
+}
+
+/// Write the new tokens, keeping the other fields of an existing token
+/// file (user, per-token overrides), encrypted if so configured.
+fn write_token_file(config: &Config, token_path: &str, resp: TokenResponse) -> Result<(), String> {
+    let access_token = resp.access_token.ok_or("token response has no access_token")?;
+    let refresh_token = resp.refresh_token.ok_or("token response has no refresh_token")?;
+    let expires_in = resp.expires_in.ok_or("token response has no expires_in")?;
+
+    let (existing, encrypted) = match read_token_file(token_path, config) {
+        Ok((contents, encrypted)) => (serde_json::from_slice(&contents).ok(), encrypted),
+        // Encrypted, and no key to read it with: replacing it would leave
+        // the new tokens in plaintext.
+        Err(_) if config.token_encryption_key.is_none()
+            && fs::read(token_path).is_ok_and(|contents| crypto::is_encrypted(&contents)) =>
+        {
+            return Err(format!(
+                "{} is encrypted but token_encryption_key_file is not set, refusing to \
+                 overwrite it",
+                token_path
+            ));
+        }
+        Err(_) => (None, false),
+    };
+    let mut token: serde_json::Map<String, serde_json::Value> = existing.unwrap_or_default();
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+    token.remove("token");
+    token.insert("access_token".to_string(), access_token.into());
+    token.insert("refresh_token".to_string(), refresh_token.into());
+    token.insert("expiry".to_string(), (now + expires_in).to_string().into());
+    token.insert("expires_in".to_string(), expires_in.to_string().into());
+    let json = serde_json::to_string_pretty(&token).map_err(|e| e.to_string())?;
+    let contents = crypto::seal(config, json.into_bytes(), encrypted)?;
+
+    let temp_path = format!("{}.{}.tmp", token_path, std::process::id());
+    let mut f = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&temp_path)
+        .map_err(|e| format!("creating {}: {}", temp_path, e))?;
+    f.write_all(&contents)
+        .and_then(|()| f.sync_all())
+        .and_then(|()| fs::rename(&temp_path, token_path))
+        .map_err(|e| {
+            let _ = fs::remove_file(&temp_path);
+            format!("writing {}: {}", token_path, e)
+        })?;
+    // Best effort: the new file is in place whether or not this succeeds.
+    let _ = sync_parent_dir(token_path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{fake_endpoint, test_config};
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_device_flow() {
+        let (url, server) = fake_endpoint(vec![
+            (
+                200,
+                r#"{"device_code":"dc","user_code":"ABCD-EFGH",
+                    "verification_uri":"https://example.com/device","expires_in":60,
+                    "interval":0}"#
+                    .to_string(),
+            ),
+            (400, r#"{"error":"authorization_pending"}"#.to_string()),
+            (
+                200,
+                r#"{"access_token":"at","refresh_token":"rt","expires_in":3600}"#.to_string(),
+            ),
+        ]);
+        let config = test_config(&format!(
+            r#"{{ "client_id": "id", "scope": "offline_access", "token_endpoint": "{0}",
+                  "device_authorization_endpoint": "{0}" }}"#,
+            url
+        ));
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("token.json");
+        let path = path.to_str().unwrap();
+        fs::write(path, r#"{ "refresh_token": "revoked", "user": "me@example.com" }"#).unwrap();
+
+        let code = request_device_code(config).unwrap();
+        assert_eq!(code.user_code, "ABCD-EFGH");
+        assert_eq!(code.verification_uri, "https://example.com/device");
+        poll_and_write(config, &code, path).unwrap();
+
+        let requests = server.join().unwrap();
+        assert!(requests[0].ends_with("client_id=id&scope=offline_access"));
+        assert!(requests[1].contains("device_code=dc"));
+        assert!(requests[1].contains("grant-type%3Adevice_code"));
+
+        let written: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap();
+        assert_eq!(written["access_token"], "at");
+        assert_eq!(written["refresh_token"], "rt");
+        assert_eq!(written["user"], "me@example.com");
+        assert_eq!(written["expires_in"], "3600");
+        let mode = fs::metadata(path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn test_device_flow_denied() {
+        let (url, server) = fake_endpoint(vec![(
+            400,
+            r#"{"error":"access_denied","error_description":"user declined"}"#.to_string(),
+        )]);
+        let config = test_config(&format!(r#"{{ "token_endpoint": "{}" }}"#, url));
+        let code: DeviceCode = serde_json::from_str(
+            r#"{"device_code":"dc","user_code":"u","verification_url":"v","expires_in":60,
+                "interval":0}"#,
+        )
+        .unwrap();
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("token.json");
+        assert_eq!(
+            poll_and_write(config, &code, path.to_str().unwrap()),
+            Err("access_denied: user declined".to_string())
+        );
+        server.join().unwrap();
+        assert!(!path.exists());
+
+        assert!(request_device_code(test_config("{}")).is_err());
+    }
+
+    #[test]
+    fn test_encrypted_token_file_not_overwritten_without_key() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("token.json");
+        let path = path.to_str().unwrap();
+        let encrypted = b"sasl-xoauth2-aes256gcm\nsealed".to_vec();
+        assert!(crypto::is_encrypted(&encrypted));
+        fs::write(path, &encrypted).unwrap();
+
+        let resp: TokenResponse = serde_json::from_str(
+            r#"{"access_token":"at","refresh_token":"rt","expires_in":3600}"#,
+        )
+        .unwrap();
+        let err = write_token_file(test_config("{}"), path, resp).unwrap_err();
+        assert!(err.contains("refusing to overwrite"));
+        assert_eq!(fs::read(path).unwrap(), encrypted);
+    }
+}
@@ -0,0 +1,116 @@
+//! Errors inside the plugin. They become SASL result codes only where a
+//! step returns to SASL.
+
+use libc::c_int;
+use std::fmt;
+
+use crate::ffi;
+use crate::metrics::FailReason;
+
+/// Why a step, or getting an access token, failed, which decides the SASL
+/// code the MTA sees.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum XoauthError {
+    /// The token file couldn't be read or written.
+    Io,
+    /// The token endpoint couldn't be reached or was overloaded, or the
+    /// step ran out of time: the MTA should try again later.
+    Http,
+    /// A response from the token endpoint or the server wasn't usable.
+    Parse,
+    /// SASL gave no value for this, e.g. the auth name.
+    MissingField(&'static str),
+    /// This value can't be sent, e.g. a user containing a line break.
+    InvalidField(&'static str),
+    /// The token endpoint rejected the refresh token with `invalid_grant`.
+    InvalidGrant,
+    /// The token endpoint refused the refresh some other way, e.g.
+    /// `invalid_client` or a bare 403.
+    RefreshRejected,
+    /// The server rejected the access token and a fresh one is now in
+    /// place: the MTA should try again.
+    Expired,
+    /// The server rejected the access token again after
+    /// `max_token_retries` refreshes.
+    Rejected,
+    /// The configuration or a policy check refused it.
+    Config,
+    /// There was no token to send: neither a token file nor an access
+    /// token had been loaded.
+    NoToken,
+    /// A SASL callback failed with this code.
+    Sasl(c_int),
+}
+
+impl XoauthError {
+    pub fn to_sasl_code(self) -> c_int {
+        match self {
+            XoauthError::Io | XoauthError::Config | XoauthError::NoToken => ffi::SASL_FAIL,
+            XoauthError::Http | XoauthError::Expired => ffi::SASL_TRYAGAIN,
+            XoauthError::Parse => ffi::SASL_BADPROT,
+            XoauthError::MissingField(_) | XoauthError::InvalidField(_) => ffi::SASL_BADPARAM,
+            XoauthError::InvalidGrant | XoauthError::RefreshRejected | XoauthError::Rejected => {
+                ffi::SASL_BADAUTH
+            }
+            XoauthError::Sasl(code) => code,
+        }
+    }
+}
+
+impl fmt::Display for XoauthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            XoauthError::Io => write!(f, "token file I/O failed"),
+            XoauthError::Http => write!(f, "token endpoint unavailable"),
+            XoauthError::Parse => write!(f, "unusable response"),
+            XoauthError::MissingField(what) => write!(f, "missing {}", what),
+            XoauthError::InvalidField(what) => write!(f, "invalid {}", what),
+            XoauthError::InvalidGrant => write!(f, "refresh token rejected by the token endpoint"),
+            XoauthError::RefreshRejected => write!(f, "refresh refused by the token endpoint"),
+            XoauthError::Expired => write!(f, "access token rejected, refreshed"),
+            XoauthError::Rejected => write!(f, "access token rejected by the server"),
+            XoauthError::Config => write!(f, "refused by configuration"),
+            XoauthError::NoToken => write!(f, "no token loaded"),
+            XoauthError::Sasl(code) => write!(f, "SASL error {}", code),
+        }
+    }
+}
+
+impl std::error::Error for XoauthError {}
+
+impl From<FailReason> for XoauthError {
+    fn from(reason: FailReason) -> Self {
+        match reason {
+            FailReason::Network
+            | FailReason::ServerError
+            | FailReason::RateLimited
+            | FailReason::Timeout => XoauthError::Http,
+            FailReason::InvalidGrant => XoauthError::InvalidGrant,
+            FailReason::Rejected => XoauthError::RefreshRejected,
+            FailReason::BadResponse => XoauthError::Parse,
+            FailReason::WriteFailed => XoauthError::Io,
+            FailReason::Other => XoauthError::Config,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sasl_codes() {
+        assert_eq!(XoauthError::from(FailReason::Network).to_sasl_code(), ffi::SASL_TRYAGAIN);
+        assert_eq!(XoauthError::from(FailReason::Timeout).to_sasl_code(), ffi::SASL_TRYAGAIN);
+        assert_eq!(XoauthError::from(FailReason::InvalidGrant).to_sasl_code(), ffi::SASL_BADAUTH);
+        assert_eq!(XoauthError::from(FailReason::Rejected), XoauthError::RefreshRejected);
+        assert_eq!(XoauthError::RefreshRejected.to_sasl_code(), ffi::SASL_BADAUTH);
+        assert_eq!(XoauthError::from(FailReason::BadResponse).to_sasl_code(), ffi::SASL_BADPROT);
+        assert_eq!(XoauthError::from(FailReason::WriteFailed).to_sasl_code(), ffi::SASL_FAIL);
+        assert_eq!(XoauthError::MissingField("auth name").to_sasl_code(), ffi::SASL_BADPARAM);
+        assert_eq!(XoauthError::Rejected.to_sasl_code(), ffi::SASL_BADAUTH);
+        assert_eq!(XoauthError::NoToken.to_sasl_code(), ffi::SASL_FAIL);
+        assert_eq!(XoauthError::Sasl(ffi::SASL_NOMEM).to_sasl_code(), ffi::SASL_NOMEM);
+        assert_eq!(XoauthError::MissingField("auth name").to_string(), "missing auth name");
+    }
+}
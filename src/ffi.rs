@@ -40,6 +40,11 @@ pub type sasl_getsecret_t = unsafe extern "C" fn(
     psecret: *mut *mut sasl_secret_t,
 ) -> c_int;
 
+/// `sasl_auxprop_getctx` from libsasl2, which has the connection's
+/// auxiliary property context. Looked up at run time, since the plugin
+/// doesn't link libsasl2 itself.
+pub type sasl_auxprop_getctx_t = unsafe extern "C" fn(conn: *mut sasl_conn_t) -> *mut propctx;
+
 // Safety: the plugin struct contains only function pointers and a const string
 // pointer. It is initialized once and never mutated.
 unsafe impl Sync for sasl_client_plug_t {}
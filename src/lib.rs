@@ -1,31 +1,77 @@
-//! SASL XOAUTH2 plugin entry point.
+//! SASL XOAUTH2/OAUTHBEARER plugin entry point.
 //!
 //! Exports `sasl_client_plug_init` for Cyrus SASL to discover and load.
 
+pub mod api;
 mod client;
 pub mod config;
+mod crypto;
+pub mod device_flow;
+pub mod error;
 mod ffi;
 pub mod log;
+mod metrics;
+#[cfg(test)]
+mod test_util;
 pub mod token_store;
 
 use libc::{c_char, c_int, c_uint, c_void};
+use std::ffi::CStr;
+use std::panic::{self, AssertUnwindSafe};
 use std::ptr;
+use std::sync::OnceLock;
 
-use crate::client::Client;
+use crate::client::{Client, Mechanism};
+use crate::config::Config;
 use crate::ffi::*;
+use crate::log::{Log, LogMode};
 
 // ---------------------------------------------------------------------------
 // Plugin callback functions (C ABI)
 // ---------------------------------------------------------------------------
 
-/// Called by SASL when a new authentication exchange begins.
-unsafe extern "C" fn mech_new(
+/// Run the body of a C ABI callback, turning a panic into `SASL_FAIL`:
+/// unwinding into SASL's C frames is undefined behavior.
+fn catch_panic(name: &str, f: impl FnOnce() -> c_int) -> c_int {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(err) => err,
+        Err(payload) => {
+            let msg = payload
+                .downcast_ref::<&str>()
+                .copied()
+                .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+                .unwrap_or("unknown panic");
+            Log::new(LogMode::Immediate).write(format!("{}: panicked: {}", name, msg));
+            SASL_FAIL
+        }
+    }
+}
+
+/// Called by SASL when a new XOAUTH2 authentication exchange begins.
+unsafe extern "C" fn mech_new_xoauth2(
     _glob_context: *mut c_void,
     _params: *mut sasl_client_params_t,
     context: *mut *mut c_void,
 ) -> c_int {
-    let client = Box::new(Client::new());
-    *context = Box::into_raw(client) as *mut c_void;
+    catch_panic("mech_new", || mech_new(Mechanism::XOAuth2, context))
+}
+
+/// Called by SASL when a new OAUTHBEARER authentication exchange begins.
+unsafe extern "C" fn mech_new_oauthbearer(
+    _glob_context: *mut c_void,
+    _params: *mut sasl_client_params_t,
+    context: *mut *mut c_void,
+) -> c_int {
+    catch_panic("mech_new", || mech_new(Mechanism::OAuthBearer, context))
+}
+
+unsafe fn mech_new(mechanism: Mechanism, context: *mut *mut c_void) -> c_int {
+    let Some(client) = Client::new(mechanism) else {
+        // Only possible if plugin init failed to load the config.
+        Log::new(LogMode::Immediate).write("mech_new: config not initialized");
+        return SASL_FAIL;
+    };
+    *context = Box::into_raw(Box::new(client)) as *mut c_void;
     SASL_OK
 }
 
@@ -44,48 +90,76 @@ unsafe extern "C" fn mech_step(
         return SASL_BADPARAM;
     }
     let client = &mut *(context as *mut Client);
-    client.do_step(
-        params,
-        from_server,
-        from_server_len,
-        prompt_need,
-        to_server,
-        to_server_len,
-        out_params,
-    )
+    catch_panic("mech_step", || {
+        client.do_step(
+            params,
+            from_server,
+            from_server_len,
+            prompt_need,
+            to_server,
+            to_server_len,
+            out_params,
+        )
+    })
 }
 
 /// Called by SASL when the authentication exchange is done (cleanup).
 unsafe extern "C" fn mech_dispose(context: *mut c_void, _utils: *const sasl_utils_t) {
     if !context.is_null() {
         // Reconstruct the Box so it gets dropped properly
-        let _ = Box::from_raw(context as *mut Client);
+        let client = Box::from_raw(context as *mut Client);
+        catch_panic("mech_dispose", move || {
+            drop(client);
+            SASL_OK
+        });
     }
 }
 
 // ---------------------------------------------------------------------------
-// Static plugin descriptor
+// Plugin descriptor
 // ---------------------------------------------------------------------------
 
-/// The mechanism name as a C string (must be 'static and null-terminated).
-static MECH_NAME: &[u8] = b"XOAUTH2\0";
-
-/// Plugin descriptor — static, lives for the lifetime of the process.
-static PLUGIN: sasl_client_plug_t = sasl_client_plug_t {
-    mech_name: MECH_NAME.as_ptr() as *const c_char,
-    max_ssf: 60,
-    security_flags: (SASL_SEC_NOANONYMOUS | SASL_SEC_PASS_CREDENTIALS) as u32,
-    features: (SASL_FEAT_WANT_CLIENT_FIRST | SASL_FEAT_ALLOWS_PROXY) as u32,
-    required_prompts: ptr::null(),
-    glob_context: ptr::null_mut(),
-    mech_new: Some(mech_new),
-    mech_step: Some(mech_step),
-    mech_dispose: Some(mech_dispose),
-    mech_free: None,
-    idle: None,
-    spare_fptr1: None,
-    spare_fptr2: None,
-};
+/// Build the descriptor for one mechanism; both share the same step logic.
+fn plugin(
+    config: &Config,
+    mechanism: Mechanism,
+    mech_new: unsafe extern "C" fn(
+        *mut c_void,
+        *mut sasl_client_params_t,
+        *mut *mut c_void,
+    ) -> c_int,
+) -> sasl_client_plug_t {
+    sasl_client_plug_t {
+        mech_name: mechanism.name().as_ptr(),
+        max_ssf: config.max_ssf,
+        security_flags: config.security_flags(),
+        features: (SASL_FEAT_WANT_CLIENT_FIRST | SASL_FEAT_ALLOWS_PROXY) as u32,
+        required_prompts: ptr::null(),
+        glob_context: ptr::null_mut(),
+        mech_new: Some(mech_new),
+        mech_step: Some(mech_step),
+        mech_dispose: Some(mech_dispose),
+        mech_free: None,
+        idle: None,
+        spare_fptr1: None,
+        spare_fptr2: None,
+    }
+}
+
+/// Plugin descriptors, built from the config at init and live for the
+/// lifetime of the process.
+static PLUGINS: OnceLock<[sasl_client_plug_t; 2]> = OnceLock::new();
+
+fn plugins(config: &Config) -> &'static [sasl_client_plug_t; 2] {
+    PLUGINS.get_or_init(|| descriptors(config))
+}
+
+fn descriptors(config: &Config) -> [sasl_client_plug_t; 2] {
+    [
+        plugin(config, Mechanism::XOAuth2, mech_new_xoauth2),
+        plugin(config, Mechanism::OAuthBearer, mech_new_oauthbearer),
+    ]
+}
 
 // ---------------------------------------------------------------------------
 // Exported entry point
@@ -103,30 +177,170 @@ pub unsafe extern "C" fn sasl_client_plug_init(
     plug_list: *mut *const sasl_client_plug_t,
     plug_count: *mut c_int,
 ) -> c_int {
-    if max_version < SASL_CLIENT_PLUG_VERSION {
-        if !utils.is_null() {
-            if let Some(seterror) = (*utils).seterror {
-                let msg = b"sasl-xoauth2: need version %d, got %d\0";
-                seterror(
-                    (*utils).conn,
-                    0,
-                    msg.as_ptr() as *const c_char,
-                    SASL_CLIENT_PLUG_VERSION,
-                    max_version,
-                );
+    catch_panic("sasl_client_plug_init", || {
+        if max_version < SASL_CLIENT_PLUG_VERSION {
+            if !utils.is_null() {
+                if let Some(seterror) = (*utils).seterror {
+                    let msg = b"sasl-xoauth2: need version %d, got %d\0";
+                    seterror(
+                        (*utils).conn,
+                        0,
+                        msg.as_ptr() as *const c_char,
+                        SASL_CLIENT_PLUG_VERSION,
+                        max_version,
+                    );
+                }
             }
+            return SASL_BADVERS;
         }
-        return SASL_BADVERS;
+
+        // Initialize config before chroot (Postfix chroots after plugin init).
+        let err = Config::init();
+        if err != SASL_OK {
+            return err;
+        }
+        let Some(config) = Config::get() else {
+            return SASL_FAIL;
+        };
+        log::init_syslog(config.syslog_facility, &config.syslog_ident);
+        log::init_log_file(&config.log_file);
+
+        let plugins = plugins(config);
+        *out_version = SASL_CLIENT_PLUG_VERSION;
+        *plug_list = plugins.as_ptr();
+        *plug_count = plugins.len() as c_int;
+        SASL_OK
+    })
+}
+
+/// Version and build info, NUL-terminated for `sasl_xoauth2_version`.
+const VERSION: &str = concat!(
+    env!("CARGO_PKG_VERSION"),
+    " (git ",
+    env!("SASL_XOAUTH2_GIT_HASH"),
+    ")\0"
+);
+
+/// The plugin's version and build info, e.g. `0.1.0 (git 1a2b3c4d5e6f)`.
+pub fn version() -> &'static str {
+    VERSION.trim_end_matches('\0')
+}
+
+/// The plugin's version and build info as a static NUL-terminated string,
+/// for packagers to check which build is installed.
+#[no_mangle]
+pub extern "C" fn sasl_xoauth2_version() -> *const c_char {
+    VERSION.as_ptr() as *const c_char
+}
+
+/// Self-test for packagers: check that the plugin loads, that the config
+/// at `config_path` (or `Config::path()` if null) parses, and that a client
+/// can be set up for each mechanism, without a SASL library or Postfix.
+/// Returns `SASL_OK`, or `SASL_FAIL` with the reason on stderr.
+///
+/// # Safety
+/// `config_path` must be null or a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn sasl_xoauth2_selftest(config_path: *const c_char) -> c_int {
+    catch_panic("sasl_xoauth2_selftest", || {
+        let path = if config_path.is_null() {
+            Config::path()
+        } else {
+            CStr::from_ptr(config_path).to_string_lossy().into_owned()
+        };
+        // Checked on its own, not through (or into) the global config, which
+        // may already hold a different file.
+        let config = match Config::load(&path) {
+            Ok(config) => config,
+            Err(e) => {
+                eprintln!("sasl-xoauth2: {}", e);
+                return SASL_FAIL;
+            }
+        };
+        // Clients keep a reference; the self-test runs once per process.
+        let config: &'static Config = Box::leak(Box::new(config));
+        if let Err(e) = config.agent() {
+            eprintln!("sasl-xoauth2: selftest: {}", e);
+            return SASL_FAIL;
+        }
+        let plugins = descriptors(config);
+        for mechanism in [Mechanism::XOAuth2, Mechanism::OAuthBearer] {
+            if !plugins.iter().any(|p| CStr::from_ptr(p.mech_name) == mechanism.name()) {
+                eprintln!("sasl-xoauth2: selftest: no plugin for {:?}", mechanism.name());
+                return SASL_FAIL;
+            }
+            drop(Client::with_config(config, mechanism));
+        }
+        SASL_OK
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::test_config;
+
+    #[test]
+    fn test_catch_panic() {
+        assert_eq!(catch_panic("test", || SASL_CONTINUE), SASL_CONTINUE);
+        assert_eq!(catch_panic("test", || panic!("bad token file")), SASL_FAIL);
+        assert_eq!(
+            catch_panic("test", || panic!("{}", String::from("formatted"))),
+            SASL_FAIL
+        );
     }
 
-    // Initialize config before chroot (Postfix chroots after plugin init).
-    let err = config::Config::init();
-    if err != SASL_OK {
-        return err;
+    #[test]
+    fn test_plugin_descriptor() {
+        let p = plugin(test_config("{}"), Mechanism::XOAuth2, mech_new_xoauth2);
+        assert_eq!(unsafe { CStr::from_ptr(p.mech_name) }, c"XOAUTH2");
+        assert_eq!(p.max_ssf, 60);
+        assert_eq!(
+            p.security_flags,
+            (SASL_SEC_NOANONYMOUS | SASL_SEC_PASS_CREDENTIALS) as u32
+        );
+
+        let config = test_config(
+            r#"{ "max_ssf": 0, "sec_noplaintext": true, "sec_pass_credentials": false }"#,
+        );
+        let p = plugin(config, Mechanism::OAuthBearer, mech_new_oauthbearer);
+        assert_eq!(p.max_ssf, 0);
+        assert_eq!(p.security_flags, (SASL_SEC_NOPLAINTEXT | SASL_SEC_NOANONYMOUS) as u32);
     }
 
-    *out_version = SASL_CLIENT_PLUG_VERSION;
-    *plug_list = &PLUGIN as *const sasl_client_plug_t;
-    *plug_count = 1;
-    SASL_OK
+    #[test]
+    fn test_version() {
+        let exported = unsafe { CStr::from_ptr(sasl_xoauth2_version()) };
+        assert_eq!(exported.to_str().unwrap(), version());
+        assert!(version().starts_with(concat!(env!("CARGO_PKG_VERSION"), " (git ")));
+        assert!(version().ends_with(')'));
+    }
+
+    #[test]
+    fn test_selftest() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("sasl-xoauth2.conf");
+        std::fs::write(&path, r#"{ "client_id": "id" }"#).unwrap();
+        let path = std::ffi::CString::new(path.to_str().unwrap()).unwrap();
+        assert_eq!(unsafe { sasl_xoauth2_selftest(path.as_ptr()) }, SASL_OK);
+
+        let missing = c"/nonexistent/sasl-xoauth2.conf";
+        assert_eq!(unsafe { sasl_xoauth2_selftest(missing.as_ptr()) }, SASL_FAIL);
+    }
+
+    #[test]
+    fn test_selftest_checks_its_own_config() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let good = dir.path().join("good.conf");
+        std::fs::write(&good, r#"{ "client_id": "id" }"#).unwrap();
+        Config::init_from_path(good.to_str().unwrap());
+        assert!(Config::is_initialized());
+
+        // Parses, but the HTTP client can't be set up: a global config that
+        // could be must not stand in for it.
+        let bad = dir.path().join("bad.conf");
+        std::fs::write(&bad, r#"{ "ca_bundle_path": "/nonexistent/ca.pem" }"#).unwrap();
+        let bad = std::ffi::CString::new(bad.to_str().unwrap()).unwrap();
+        assert_eq!(unsafe { sasl_xoauth2_selftest(bad.as_ptr()) }, SASL_FAIL);
+    }
 }
@@ -1,12 +1,24 @@
 //! Logging wrapper with optional syslog output.
 //!
-//! Supports three modes:
+//! Supports these modes:
 //! - Immediate: always write to syslog
 //! - OnFailure: buffer lines, flush to syslog on failure
+//! - JsonStderr: write each line to stderr as a JSON object
 //! - None: discard all log output
+//!
+//! Lines below the logger's `LogLevel` threshold are dropped in every mode.
+//! Syslog lines go to the facility set by `init_syslog` (mail by default),
+//! and are also appended to the file set by `init_log_file`, if any.
 
+use serde::Deserialize;
+use std::ffi::CString;
 use std::fmt;
-use std::sync::Mutex;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 /// Logging mode.
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -17,39 +29,290 @@ pub enum LogMode {
     OnFailure,
     /// Buffer the full trace, flush everything on failure.
     FullTraceOnFailure,
+    /// Write each line immediately to stderr as JSON, tagged with the
+    /// session's context.
+    JsonStderr,
     /// Discard all output.
     None,
 }
 
+/// Severity of a log line.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogLevel {
+    /// Per-step tracing.
+    #[default]
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl LogLevel {
+    fn name(self) -> &'static str {
+        match self {
+            LogLevel::Debug => "debug",
+            LogLevel::Info => "info",
+            LogLevel::Warn => "warn",
+            LogLevel::Error => "error",
+        }
+    }
+
+    /// Syslog priority: `facility` | the matching severity.
+    fn syslog_priority(self, facility: libc::c_int) -> libc::c_int {
+        facility
+            | match self {
+                LogLevel::Debug => libc::LOG_DEBUG,
+                LogLevel::Info => libc::LOG_INFO,
+                LogLevel::Warn => libc::LOG_WARNING,
+                LogLevel::Error => libc::LOG_ERR,
+            }
+    }
+}
+
+/// Syslog facility for log lines.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SyslogFacility {
+    #[default]
+    Mail,
+    Auth,
+    Authpriv,
+    Daemon,
+    User,
+    Local0,
+    Local1,
+    Local2,
+    Local3,
+    Local4,
+    Local5,
+    Local6,
+    Local7,
+}
+
+impl SyslogFacility {
+    fn to_libc(self) -> libc::c_int {
+        match self {
+            SyslogFacility::Mail => libc::LOG_MAIL,
+            SyslogFacility::Auth => libc::LOG_AUTH,
+            SyslogFacility::Authpriv => libc::LOG_AUTHPRIV,
+            SyslogFacility::Daemon => libc::LOG_DAEMON,
+            SyslogFacility::User => libc::LOG_USER,
+            SyslogFacility::Local0 => libc::LOG_LOCAL0,
+            SyslogFacility::Local1 => libc::LOG_LOCAL1,
+            SyslogFacility::Local2 => libc::LOG_LOCAL2,
+            SyslogFacility::Local3 => libc::LOG_LOCAL3,
+            SyslogFacility::Local4 => libc::LOG_LOCAL4,
+            SyslogFacility::Local5 => libc::LOG_LOCAL5,
+            SyslogFacility::Local6 => libc::LOG_LOCAL6,
+            SyslogFacility::Local7 => libc::LOG_LOCAL7,
+        }
+    }
+}
+
+/// Facility syslog lines are written to.
+static FACILITY: AtomicI32 = AtomicI32::new(libc::LOG_MAIL);
+
+/// Ident passed to `openlog`, kept alive for syslog's use. Once set, lines
+/// no longer carry their own `sasl-xoauth2: ` prefix.
+static IDENT: OnceLock<CString> = OnceLock::new();
+
+/// Set the syslog facility, and with a non-empty `ident`, call `openlog`.
+/// `openlog` is process-wide, so the ident applies to the host's own
+/// syslog lines too (e.g. Postfix's).
+pub fn init_syslog(facility: SyslogFacility, ident: &str) {
+    FACILITY.store(facility.to_libc(), Ordering::Relaxed);
+    if ident.is_empty() {
+        return;
+    }
+    let Ok(ident) = CString::new(ident) else {
+        return;
+    };
+    if IDENT.set(ident).is_ok() {
+        if let Some(ident) = IDENT.get() {
+            unsafe {
+                libc::openlog(ident.as_ptr(), libc::LOG_PID, facility.to_libc());
+            }
+        }
+    }
+}
+
+/// File syslog lines are also appended to, from `log_file`.
+static LOG_FILE: OnceLock<Mutex<fs::File>> = OnceLock::new();
+
+/// Append syslog lines to `path` as well, with a timestamp. Opened now,
+/// before Postfix chroots; if it can't be, lines only go to syslog.
+pub fn init_log_file(path: &str) {
+    if path.is_empty() || LOG_FILE.get().is_some() {
+        return;
+    }
+    match fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .mode(0o600)
+        .open(path)
+    {
+        Ok(file) => {
+            let _ = LOG_FILE.set(Mutex::new(file));
+        }
+        Err(e) => Log::write_to_syslog(
+            LogLevel::Warn,
+            &format!("sasl-xoauth2: can't open log_file {}: {}", path, e),
+        ),
+    }
+}
+
+/// `secs` since the Unix epoch as an RFC 3339 UTC timestamp.
+fn utc_timestamp(secs: u64) -> String {
+    // Civil date from days since the epoch (Howard Hinnant's algorithm).
+    let days = (secs / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let doe = days.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    let secs_of_day = secs % 86400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60
+    )
+}
+
+/// Characters `redact` leaves visible at the end of a value.
+const REDACT_VISIBLE_CHARS: usize = 4;
+
+/// Mask a sensitive value for logging, keeping only its last few
+/// characters. Values too short for that to be safe are masked entirely.
+pub fn redact(value: &str) -> String {
+    let len = value.chars().count();
+    if len <= 2 * REDACT_VISIBLE_CHARS {
+        return "*".repeat(len);
+    }
+    let tail: String = value.chars().skip(len - REDACT_VISIBLE_CHARS).collect();
+    format!("{}{}", "*".repeat(len - REDACT_VISIBLE_CHARS), tail)
+}
+
 /// Logger instance (one per SASL client session).
 pub struct Log {
     mode: LogMode,
-    lines: Mutex<Vec<String>>,
+    level: LogLevel,
+    lines: Mutex<Vec<(LogLevel, String)>>,
     flush_on_destroy: Mutex<bool>,
+    context: Mutex<LogContext>,
+    /// Lines sent by `write_always` (for tests).
+    #[cfg(test)]
+    always: Mutex<Vec<String>>,
+}
+
+/// Session details attached to JSON log lines.
+#[derive(Default)]
+struct LogContext {
+    user: Option<String>,
+    token_path: Option<String>,
 }
 
 impl Log {
     pub fn new(mode: LogMode) -> Self {
+        Self::with_level(mode, LogLevel::Debug)
+    }
+
+    /// Create a logger that drops lines below `level`.
+    pub fn with_level(mode: LogMode, level: LogLevel) -> Self {
         Self {
             mode,
+            level,
             lines: Mutex::new(Vec::new()),
             flush_on_destroy: Mutex::new(false),
+            context: Mutex::new(LogContext::default()),
+            #[cfg(test)]
+            always: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Tag later lines with the user being authenticated.
+    pub fn set_user(&self, user: &str) {
+        if let Ok(mut context) = self.context.lock() {
+            context.user = Some(user.to_string());
+        }
+    }
+
+    /// Tag later lines with the token file in use.
+    pub fn set_token_path(&self, path: &str) {
+        if let Ok(mut context) = self.context.lock() {
+            context.token_path = Some(path.to_string());
         }
     }
 
-    /// Write a log message.
+    /// Write a log message at `Info`.
     pub fn write(&self, msg: impl fmt::Display) {
+        self.write_level(LogLevel::Info, msg);
+    }
+
+    /// Write a log message at `level`, if it meets the threshold.
+    pub fn write_level(&self, level: LogLevel, msg: impl fmt::Display) {
+        if level < self.level {
+            return;
+        }
+        if self.mode == LogMode::JsonStderr {
+            eprintln!("{}", self.json_line(level, &msg.to_string()));
+            return;
+        }
         let message = format!("sasl-xoauth2: {}", msg);
         match self.mode {
             LogMode::Immediate => {
-                Self::write_to_syslog(&message);
+                Self::emit(level, &message);
             }
             LogMode::OnFailure | LogMode::FullTraceOnFailure => {
                 if let Ok(mut lines) = self.lines.lock() {
-                    lines.push(message);
+                    lines.push((level, message));
                 }
             }
-            LogMode::None => {}
+            LogMode::JsonStderr | LogMode::None => {}
+        }
+    }
+
+    /// Format `msg` as a JSON log line.
+    pub(crate) fn json_line(&self, level: LogLevel, msg: &str) -> String {
+        let ts = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let mut line = serde_json::json!({
+            "ts": ts,
+            "level": level.name(),
+            "source": "sasl-xoauth2",
+            "msg": msg,
+        });
+        if let Ok(context) = self.context.lock() {
+            if let Some(ref user) = context.user {
+                line["user"] = user.as_str().into();
+            }
+            if let Some(ref path) = context.token_path {
+                line["token_path"] = path.as_str().into();
+            }
+        }
+        line.to_string()
+    }
+
+    /// Write a message to syslog immediately, whatever the mode or level.
+    pub fn write_always(&self, msg: impl fmt::Display) {
+        let message = format!("sasl-xoauth2: {}", msg);
+        if self.mode == LogMode::JsonStderr {
+            eprintln!("{}", self.json_line(LogLevel::Info, &msg.to_string()));
+        } else {
+            Self::emit(LogLevel::Info, &message);
+        }
+        #[cfg(test)]
+        if let Ok(mut always) = self.always.lock() {
+            always.push(message);
         }
     }
 
@@ -60,19 +323,19 @@ impl Log {
         }
     }
 
-    /// Flush buffered logs to syslog.
+    /// Flush buffered logs to syslog and the log file.
     pub fn flush(&self) {
         if let Ok(lines) = self.lines.lock() {
             match self.mode {
                 LogMode::FullTraceOnFailure => {
-                    for line in lines.iter() {
-                        Self::write_to_syslog(line);
+                    for (level, line) in lines.iter() {
+                        Self::emit(*level, line);
                     }
                 }
                 LogMode::OnFailure => {
                     // Write a summary of the last few lines
-                    if let Some(last) = lines.last() {
-                        Self::write_to_syslog(last);
+                    if let Some((level, last)) = lines.last() {
+                        Self::emit(*level, last);
                     }
                 }
                 _ => {}
@@ -80,17 +343,57 @@ impl Log {
         }
     }
 
-    fn write_to_syslog(msg: &str) {
+    /// Buffered lines (for tests).
+    #[cfg(test)]
+    pub(crate) fn lines(&self) -> Vec<String> {
+        self.lines
+            .lock()
+            .map(|l| l.iter().map(|(_, line)| line.clone()).collect())
+            .unwrap_or_default()
+    }
+
+    /// Lines sent by `write_always` (for tests).
+    #[cfg(test)]
+    pub(crate) fn always_lines(&self) -> Vec<String> {
+        self.always.lock().map(|l| l.clone()).unwrap_or_default()
+    }
+
+    /// Write a line to syslog, and to the log file if there is one.
+    fn emit(level: LogLevel, msg: &str) {
+        Self::write_to_syslog(level, msg);
+        if let Some(file) = LOG_FILE.get() {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            if let Ok(mut file) = file.lock() {
+                // One write per line, so O_APPEND keeps processes' lines whole.
+                let line = format!("{} {} {}\n", utc_timestamp(now), level.name(), msg);
+                let _ = file.write_all(line.as_bytes());
+            }
+        }
+    }
+
+    fn write_to_syslog(level: LogLevel, msg: &str) {
         // Use libc syslog directly since we're in a shared library context.
-        // LOG_MAIL (2<<3 = 16) | LOG_WARNING (4)
-        let c_msg = std::ffi::CString::new(msg).unwrap_or_default();
-        let fmt = std::ffi::CString::new("%s").unwrap();
+        let msg = match IDENT.get() {
+            Some(_) => msg.strip_prefix("sasl-xoauth2: ").unwrap_or(msg),
+            None => msg,
+        };
+        let c_msg = syslog_message(msg);
+        let priority = level.syslog_priority(FACILITY.load(Ordering::Relaxed));
         unsafe {
-            libc::syslog(16 | 4, fmt.as_ptr(), c_msg.as_ptr());
+            libc::syslog(priority, c"%s".as_ptr(), c_msg.as_ptr());
         }
     }
 }
 
+/// `msg` as a C string, with interior NULs (e.g. from a binary token
+/// file) written as `\0` so the line isn't lost.
+fn syslog_message(msg: &str) -> CString {
+    CString::new(msg.replace('\0', "\\0")).unwrap_or_default()
+}
+
 impl Drop for Log {
     fn drop(&mut self) {
         if let Ok(f) = self.flush_on_destroy.lock() {
@@ -100,3 +403,98 @@ impl Drop for Log {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_syslog_message() {
+        assert_eq!(syslog_message("plain").as_bytes(), b"plain");
+        assert_eq!(syslog_message("bad\0token\0").as_bytes(), b"bad\\0token\\0");
+    }
+
+    #[test]
+    fn test_json_line() {
+        let log = Log::new(LogMode::JsonStderr);
+        let line: serde_json::Value =
+            serde_json::from_str(&log.json_line(LogLevel::Info, "Client: created")).unwrap();
+        assert_eq!(line["msg"], "Client: created");
+        assert_eq!(line["level"], "info");
+        assert!(line["ts"].as_u64().unwrap() > 0);
+        assert!(line.get("user").is_none());
+
+        log.set_user("user@example.com");
+        log.set_token_path("/etc/tokens/user");
+        let line: serde_json::Value =
+            serde_json::from_str(&log.json_line(LogLevel::Warn, "\"quoted\"")).unwrap();
+        assert_eq!(line["msg"], "\"quoted\"");
+        assert_eq!(line["level"], "warn");
+        assert_eq!(line["user"], "user@example.com");
+        assert_eq!(line["token_path"], "/etc/tokens/user");
+
+        // JSON mode keeps nothing buffered for a syslog flush.
+        log.write("hello");
+        assert!(log.lines().is_empty());
+    }
+
+    #[test]
+    fn test_utc_timestamp() {
+        assert_eq!(utc_timestamp(0), "1970-01-01T00:00:00Z");
+        assert_eq!(utc_timestamp(951_782_400), "2000-02-29T00:00:00Z");
+        assert_eq!(utc_timestamp(1_700_000_000), "2023-11-14T22:13:20Z");
+    }
+
+    #[test]
+    fn test_log_file() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("sasl-xoauth2.log");
+        init_log_file(path.to_str().unwrap());
+
+        let log = Log::new(LogMode::FullTraceOnFailure);
+        log.write("buffered");
+        assert!(!fs::read_to_string(&path).unwrap().contains("buffered"));
+        log.flush();
+        Log::new(LogMode::Immediate).write_level(LogLevel::Warn, "immediate");
+
+        let text = fs::read_to_string(&path).unwrap();
+        let line = text.lines().find(|l| l.ends_with("sasl-xoauth2: buffered")).unwrap();
+        assert!(line.contains("Z info sasl-xoauth2: buffered"));
+        assert!(text.contains(" warn sasl-xoauth2: immediate\n"));
+    }
+
+    #[test]
+    fn test_redact() {
+        assert_eq!(redact(""), "");
+        assert_eq!(redact("short"), "*****");
+        assert_eq!(redact("12345678"), "********");
+        assert_eq!(redact("/etc/tokens/user"), "************user");
+        assert_eq!(redact("ééééééééé"), "*****éééé");
+    }
+
+    #[test]
+    fn test_level_threshold() {
+        let log = Log::with_level(LogMode::FullTraceOnFailure, LogLevel::Warn);
+        log.write_level(LogLevel::Debug, "debug");
+        log.write("info");
+        log.write_level(LogLevel::Warn, "warn");
+        log.write_level(LogLevel::Error, "error");
+        assert_eq!(log.lines(), vec!["sasl-xoauth2: warn", "sasl-xoauth2: error"]);
+
+        let log = Log::new(LogMode::FullTraceOnFailure);
+        log.write_level(LogLevel::Debug, "debug");
+        assert_eq!(log.lines(), vec!["sasl-xoauth2: debug"]);
+    }
+
+    #[test]
+    fn test_syslog_priority() {
+        let mail = SyslogFacility::Mail.to_libc();
+        assert_eq!(LogLevel::Debug.syslog_priority(mail), 16 | 7);
+        assert_eq!(LogLevel::Info.syslog_priority(mail), 16 | 6);
+        assert_eq!(LogLevel::Warn.syslog_priority(mail), 16 | 4);
+        assert_eq!(LogLevel::Error.syslog_priority(mail), 16 | 3);
+        // LOG_AUTHPRIV is 10 << 3.
+        let authpriv = SyslogFacility::Authpriv.to_libc();
+        assert_eq!(LogLevel::Warn.syslog_priority(authpriv), 80 | 4);
+    }
+}
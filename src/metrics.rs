@@ -0,0 +1,288 @@
+//! Token refresh metrics, exported as a Prometheus textfile.
+//!
+//! Counts accumulate in each process and are merged into the file at
+//! `metrics_path` after every refresh, so the many short-lived processes
+//! Postfix runs add up to one set of totals. Point node_exporter's textfile
+//! collector at it (the file name must end in `.prom`).
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::token_store::FileLock;
+
+/// How long to wait for another process merging into the metrics file.
+const LOCK_WAIT: Duration = Duration::from_secs(1);
+
+/// Why a token refresh failed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FailReason {
+    /// The token endpoint couldn't be reached.
+    Network,
+    /// 5xx from the endpoint.
+    ServerError,
+    /// 429 from the endpoint.
+    RateLimited,
+    /// The endpoint rejected the refresh token.
+    InvalidGrant,
+    /// Any other error response.
+    Rejected,
+    /// A 200 response without a usable token.
+    BadResponse,
+    /// The token file couldn't be rewritten.
+    WriteFailed,
+    /// The step's block time ran out.
+    Timeout,
+    Other,
+}
+
+impl FailReason {
+    const ALL: [FailReason; 9] = [
+        FailReason::Network,
+        FailReason::ServerError,
+        FailReason::RateLimited,
+        FailReason::InvalidGrant,
+        FailReason::Rejected,
+        FailReason::BadResponse,
+        FailReason::WriteFailed,
+        FailReason::Timeout,
+        FailReason::Other,
+    ];
+
+    fn label(self) -> &'static str {
+        match self {
+            FailReason::Network => "network",
+            FailReason::ServerError => "server_error",
+            FailReason::RateLimited => "rate_limited",
+            FailReason::InvalidGrant => "invalid_grant",
+            FailReason::Rejected => "rejected",
+            FailReason::BadResponse => "bad_response",
+            FailReason::WriteFailed => "write_failed",
+            FailReason::Timeout => "timeout",
+            FailReason::Other => "other",
+        }
+    }
+}
+
+/// Counts not yet merged into the metrics file.
+struct Counts {
+    refreshes: AtomicU64,
+    successes: AtomicU64,
+    failures: [AtomicU64; FailReason::ALL.len()],
+    duration_micros: AtomicU64,
+}
+
+impl Counts {
+    const fn new() -> Self {
+        Counts {
+            refreshes: AtomicU64::new(0),
+            successes: AtomicU64::new(0),
+            failures: [const { AtomicU64::new(0) }; FailReason::ALL.len()],
+            duration_micros: AtomicU64::new(0),
+        }
+    }
+}
+
+#[cfg(not(test))]
+fn counts() -> &'static Counts {
+    static COUNTS: Counts = Counts::new();
+    &COUNTS
+}
+
+/// Per thread, so tests running in parallel don't count each other's
+/// refreshes.
+#[cfg(test)]
+fn counts() -> &'static Counts {
+    thread_local! {
+        static COUNTS: &'static Counts = Box::leak(Box::new(Counts::new()));
+    }
+    COUNTS.with(|counts| *counts)
+}
+
+/// Count one refresh and how long it took.
+pub fn record_refresh(outcome: Result<(), FailReason>, elapsed: Duration) {
+    let counts = counts();
+    counts.refreshes.fetch_add(1, Ordering::Relaxed);
+    match outcome {
+        Ok(()) => counts.successes.fetch_add(1, Ordering::Relaxed),
+        Err(reason) => counts.failures[reason as usize].fetch_add(1, Ordering::Relaxed),
+    };
+    counts.duration_micros.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+}
+
+/// Metric samples, keyed by name and labels as written in the file.
+type Samples = BTreeMap<String, f64>;
+
+fn failure_key(reason: FailReason) -> String {
+    format!(
+        "sasl_xoauth2_token_refresh_failures_total{{reason=\"{}\"}}",
+        reason.label()
+    )
+}
+
+/// Take this process's unmerged counts, resetting them.
+fn take_pending() -> Samples {
+    let counts = counts();
+    let mut pending = Samples::new();
+    let refreshes = counts.refreshes.swap(0, Ordering::Relaxed);
+    pending.insert("sasl_xoauth2_token_refreshes_total".to_string(), refreshes as f64);
+    pending.insert(
+        "sasl_xoauth2_token_refresh_successes_total".to_string(),
+        counts.successes.swap(0, Ordering::Relaxed) as f64,
+    );
+    for reason in FailReason::ALL {
+        let count = counts.failures[reason as usize].swap(0, Ordering::Relaxed);
+        pending.insert(failure_key(reason), count as f64);
+    }
+    let micros = counts.duration_micros.swap(0, Ordering::Relaxed);
+    pending.insert(
+        "sasl_xoauth2_token_refresh_duration_seconds_sum".to_string(),
+        micros as f64 / 1e6,
+    );
+    pending.insert(
+        "sasl_xoauth2_token_refresh_duration_seconds_count".to_string(),
+        refreshes as f64,
+    );
+    pending
+}
+
+/// Put counts that couldn't be merged back, for the next attempt.
+fn restore_pending(pending: &Samples) {
+    let counts = counts();
+    let get = |key: &str| pending.get(key).copied().unwrap_or(0.0);
+    counts.refreshes.fetch_add(
+        get("sasl_xoauth2_token_refreshes_total") as u64,
+        Ordering::Relaxed,
+    );
+    counts.successes.fetch_add(
+        get("sasl_xoauth2_token_refresh_successes_total") as u64,
+        Ordering::Relaxed,
+    );
+    for reason in FailReason::ALL {
+        counts.failures[reason as usize]
+            .fetch_add(get(&failure_key(reason)) as u64, Ordering::Relaxed);
+    }
+    counts.duration_micros.fetch_add(
+        (get("sasl_xoauth2_token_refresh_duration_seconds_sum") * 1e6) as u64,
+        Ordering::Relaxed,
+    );
+}
+
+/// Merge this process's counts into the metrics file at `path`.
+pub fn write_textfile(path: &str) -> std::io::Result<()> {
+    let pending = take_pending();
+    let result = merge_into_file(path, &pending);
+    if result.is_err() {
+        restore_pending(&pending);
+    }
+    result
+}
+
+/// Add `pending` to the totals in the file at `path`, under a lock so
+/// concurrent processes don't lose each other's counts.
+fn merge_into_file(path: &str, pending: &Samples) -> std::io::Result<()> {
+    let _lock = FileLock::acquire(path, true, LOCK_WAIT)?;
+    let mut totals = match fs::read_to_string(path) {
+        Ok(text) => parse(&text),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Samples::new(),
+        Err(e) => return Err(e),
+    };
+    for (key, value) in pending {
+        *totals.entry(key.clone()).or_insert(0.0) += value;
+    }
+
+    // Replace by rename, so the collector never reads a partial file.
+    let temp_path = format!("{}.{}.tmp", path, std::process::id());
+    let mut f = fs::File::create(&temp_path)?;
+    f.write_all(render(&totals).as_bytes())?;
+    drop(f);
+    fs::rename(&temp_path, path)
+}
+
+/// Read samples from Prometheus text format, skipping comments.
+fn parse(text: &str) -> Samples {
+    text.lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| {
+            let (key, value) = line.rsplit_once(' ')?;
+            Some((key.to_string(), value.parse().ok()?))
+        })
+        .collect()
+}
+
+/// Write the totals in Prometheus text exposition format.
+fn render(totals: &Samples) -> String {
+    let get = |key: &str| totals.get(key).copied().unwrap_or(0.0);
+    let mut out = String::new();
+    out.push_str("# HELP sasl_xoauth2_token_refreshes_total Token refreshes attempted.\n");
+    out.push_str("# TYPE sasl_xoauth2_token_refreshes_total counter\n");
+    out.push_str(&format!(
+        "sasl_xoauth2_token_refreshes_total {}\n",
+        get("sasl_xoauth2_token_refreshes_total")
+    ));
+    out.push_str("# HELP sasl_xoauth2_token_refresh_successes_total Token refreshes that succeeded.\n");
+    out.push_str("# TYPE sasl_xoauth2_token_refresh_successes_total counter\n");
+    out.push_str(&format!(
+        "sasl_xoauth2_token_refresh_successes_total {}\n",
+        get("sasl_xoauth2_token_refresh_successes_total")
+    ));
+    out.push_str("# HELP sasl_xoauth2_token_refresh_failures_total Token refreshes that failed, by reason.\n");
+    out.push_str("# TYPE sasl_xoauth2_token_refresh_failures_total counter\n");
+    for reason in FailReason::ALL {
+        let key = failure_key(reason);
+        out.push_str(&format!("{} {}\n", key, get(&key)));
+    }
+    out.push_str("# HELP sasl_xoauth2_token_refresh_duration_seconds Time spent refreshing tokens.\n");
+    out.push_str("# TYPE sasl_xoauth2_token_refresh_duration_seconds summary\n");
+    for key in [
+        "sasl_xoauth2_token_refresh_duration_seconds_sum",
+        "sasl_xoauth2_token_refresh_duration_seconds_count",
+    ] {
+        out.push_str(&format!("{} {}\n", key, get(key)));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn pending(successes: f64, network: f64, seconds: f64) -> Samples {
+        let mut samples = Samples::new();
+        samples.insert("sasl_xoauth2_token_refreshes_total".to_string(), successes + network);
+        samples.insert("sasl_xoauth2_token_refresh_successes_total".to_string(), successes);
+        samples.insert(failure_key(FailReason::Network), network);
+        samples.insert(
+            "sasl_xoauth2_token_refresh_duration_seconds_sum".to_string(),
+            seconds,
+        );
+        samples.insert(
+            "sasl_xoauth2_token_refresh_duration_seconds_count".to_string(),
+            successes + network,
+        );
+        samples
+    }
+
+    #[test]
+    fn test_merge_into_file() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("sasl_xoauth2.prom");
+        let path = path.to_str().unwrap();
+
+        merge_into_file(path, &pending(1.0, 0.0, 0.25)).unwrap();
+        merge_into_file(path, &pending(2.0, 1.0, 0.5)).unwrap();
+
+        let text = fs::read_to_string(path).unwrap();
+        assert!(text.contains("# TYPE sasl_xoauth2_token_refreshes_total counter\n"));
+        assert!(text.contains("\nsasl_xoauth2_token_refreshes_total 4\n"));
+        assert!(text.contains("\nsasl_xoauth2_token_refresh_successes_total 3\n"));
+        assert!(text.contains("\nsasl_xoauth2_token_refresh_failures_total{reason=\"network\"} 1\n"));
+        assert!(text.contains("\nsasl_xoauth2_token_refresh_failures_total{reason=\"timeout\"} 0\n"));
+        assert!(text.contains("\nsasl_xoauth2_token_refresh_duration_seconds_sum 0.75\n"));
+        assert!(text.contains("\nsasl_xoauth2_token_refresh_duration_seconds_count 4\n"));
+        assert_eq!(parse(&text).len(), 4 + FailReason::ALL.len());
+    }
+}
@@ -0,0 +1,89 @@
+//! Helpers shared by unit tests.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::thread::JoinHandle;
+
+use crate::config::Config;
+use crate::token_store::Clock;
+
+pub(crate) fn test_config(json: &str) -> &'static Config {
+    Box::leak(Box::new(serde_json::from_str(json).unwrap()))
+}
+
+/// A clock that only moves when told to.
+pub(crate) struct FakeClock(AtomicI64);
+
+impl FakeClock {
+    pub(crate) fn at(now: i64) -> &'static FakeClock {
+        Box::leak(Box::new(FakeClock(AtomicI64::new(now))))
+    }
+
+    pub(crate) fn advance(&self, secs: i64) {
+        self.0.fetch_add(secs, Ordering::Relaxed);
+    }
+}
+
+impl Clock for FakeClock {
+    fn now_unix(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Serve one canned `(status, body)` response per connection on a local
+/// port. The handle yields the raw requests that were received.
+pub(crate) fn fake_endpoint(responses: Vec<(u16, String)>) -> (String, JoinHandle<Vec<String>>) {
+    fake_endpoint_with_headers(
+        responses
+            .into_iter()
+            .map(|(status, body)| (status, "", body))
+            .collect(),
+    )
+}
+
+/// Like `fake_endpoint`, with extra raw header lines (each ending in
+/// `\r\n`) per response. Bodies may be binary.
+pub(crate) fn fake_endpoint_with_headers<B: AsRef<[u8]> + Send + 'static>(
+    responses: Vec<(u16, &'static str, B)>,
+) -> (String, JoinHandle<Vec<String>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/token", listener.local_addr().unwrap());
+    let handle = std::thread::spawn(move || {
+        let mut requests = Vec::new();
+        for (status, headers, body) in responses {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request = String::new();
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if let Some(v) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                    content_length = v.trim().parse().unwrap();
+                }
+                request.push_str(&line);
+                if line == "\r\n" || line.is_empty() {
+                    break;
+                }
+            }
+            let mut req_body = vec![0; content_length];
+            reader.read_exact(&mut req_body).unwrap();
+            request.push_str(&String::from_utf8_lossy(&req_body));
+            requests.push(request);
+            let body = body.as_ref();
+            write!(
+                stream,
+                "HTTP/1.1 {} X\r\nContent-Type: application/json\r\n{}\
+                 Content-Length: {}\r\nConnection: close\r\n\r\n",
+                status,
+                headers,
+                body.len()
+            )
+            .unwrap();
+            stream.write_all(body).unwrap();
+        }
+        requests
+    });
+    (url, handle)
+}